@@ -0,0 +1,171 @@
+#![cfg_attr(not(test), no_std)]
+#![deny(unsafe_code)]
+
+// IMA ADPCM codec for mono 16 bit samples, 4 bits per sample.
+// The stream has no blocks, decoder state carries over the whole clip.
+// Two samples are packed in a byte, the first one in the low nibble.
+
+const INDEX_TABLE: [i8; 8] = [-1, -1, -1, -1, 2, 4, 6, 8];
+
+const STEP_TABLE: [i32; 89] = [
+    7, 8, 9, 10, 11, 12, 13, 14, 16, 17, 19, 21, 23, 25, 28, 31, 34, 37, 41, 45, 50, 55, 60, 66,
+    73, 80, 88, 97, 107, 118, 130, 143, 157, 173, 190, 209, 230, 253, 279, 307, 337, 371, 408, 449,
+    494, 544, 598, 658, 724, 796, 876, 963, 1060, 1166, 1282, 1411, 1552, 1707, 1878, 2066, 2272,
+    2499, 2749, 3024, 3327, 3660, 4026, 4428, 4871, 5358, 5894, 6484, 7132, 7845, 8630, 9493,
+    10442, 11487, 12635, 13899, 15289, 16818, 18500, 20350, 22385, 24623, 27086, 29794, 32767,
+];
+
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct Decoder {
+    predictor: i32,
+    index: usize,
+}
+
+impl Decoder {
+    pub const fn new() -> Self {
+        Decoder {
+            predictor: 0,
+            index: 0,
+        }
+    }
+
+    pub fn decode(&mut self, nibble: u8) -> i16 {
+        let step = STEP_TABLE[self.index];
+
+        let mut diff = step >> 3;
+        if nibble & 1 != 0 {
+            diff += step >> 2;
+        }
+        if nibble & 2 != 0 {
+            diff += step >> 1;
+        }
+        if nibble & 4 != 0 {
+            diff += step;
+        }
+        if nibble & 8 != 0 {
+            diff = -diff;
+        }
+
+        self.predictor = (self.predictor + diff).clamp(i16::MIN.into(), i16::MAX.into());
+
+        let index = self.index as i32 + i32::from(INDEX_TABLE[usize::from(nibble & 7)]);
+        self.index = index.clamp(0, STEP_TABLE.len() as i32 - 1) as usize;
+
+        self.predictor as i16
+    }
+
+    // Decode both samples of a byte.
+    pub fn decode_byte(&mut self, byte: u8) -> [i16; 2] {
+        [self.decode(byte & 0xf), self.decode(byte >> 4)]
+    }
+}
+
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct Encoder {
+    // Encoder tracks the decoder to keep errors from accumulating.
+    decoder: Decoder,
+}
+
+impl Encoder {
+    pub const fn new() -> Self {
+        Encoder {
+            decoder: Decoder::new(),
+        }
+    }
+
+    pub fn encode(&mut self, sample: i16) -> u8 {
+        let mut step = STEP_TABLE[self.decoder.index];
+        let mut diff = i32::from(sample) - self.decoder.predictor;
+
+        let mut nibble = 0;
+        if diff < 0 {
+            nibble = 8;
+            diff = -diff;
+        }
+
+        for bit in [4, 2, 1] {
+            if diff >= step {
+                nibble |= bit;
+                diff -= step;
+            }
+            step >>= 1;
+        }
+
+        self.decoder.decode(nibble);
+
+        nibble
+    }
+
+    // Encode two samples into a byte.
+    pub fn encode_pair(&mut self, first: i16, second: i16) -> u8 {
+        self.encode(first) | (self.encode(second) << 4)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn sine(len: usize, amplitude: f64, period: f64) -> Vec<i16> {
+        (0..len)
+            .map(|i| (amplitude * (i as f64 * std::f64::consts::TAU / period).sin()) as i16)
+            .collect()
+    }
+
+    #[test]
+    fn test_decode() {
+        let mut decoder = Decoder::new();
+
+        assert_eq!(decoder.decode(0), 0);
+        assert_eq!(decoder.decode(7), 11);
+        assert_eq!(decoder.decode(0xf), -19);
+        assert_eq!(decoder.decode_byte(0x80), [-15, -18]);
+    }
+
+    #[test]
+    fn test_clamp() {
+        let mut decoder = Decoder::new();
+
+        for _ in 0..100 {
+            decoder.decode(7);
+        }
+        assert_eq!(decoder.decode(7), i16::MAX);
+
+        for _ in 0..100 {
+            decoder.decode(0xf);
+        }
+        assert_eq!(decoder.decode(0xf), i16::MIN);
+    }
+
+    #[test]
+    fn test_roundtrip() {
+        let samples = sine(4000, 20000.0, 40.0);
+
+        let mut encoder = Encoder::new();
+        let data: Vec<u8> = samples
+            .chunks_exact(2)
+            .map(|pair| encoder.encode_pair(pair[0], pair[1]))
+            .collect();
+        assert_eq!(data.len(), samples.len() / 2);
+
+        let mut decoder = Decoder::new();
+        let decoded: Vec<i16> = data.iter().flat_map(|&b| decoder.decode_byte(b)).collect();
+
+        // Step size needs some samples to adapt to the signal.
+        for (sample, decoded) in samples.iter().zip(decoded.iter()).skip(100) {
+            let error = (i32::from(*sample) - i32::from(*decoded)).abs();
+            assert!(error < 2000, "{} decoded as {}", sample, decoded);
+        }
+    }
+
+    #[test]
+    fn test_silence() {
+        let mut encoder = Encoder::new();
+        let mut decoder = Decoder::new();
+
+        for _ in 0..1000 {
+            let [first, second] = decoder.decode_byte(encoder.encode_pair(0, 0));
+            assert!(first.abs() <= 7 && second.abs() <= 7);
+        }
+    }
+}
@@ -0,0 +1,23 @@
+// Encode raw signed 16 bit little endian mono samples from stdin to IMA ADPCM on stdout.
+// Use with sox, e.g. sox clip.wav -b 16 -e signed-integer -L -c 1 -t raw - | adpcm-encode
+
+use adpcm::Encoder;
+use std::io::{Read, Write};
+
+fn main() -> std::io::Result<()> {
+    let mut input = Vec::new();
+    std::io::stdin().read_to_end(&mut input)?;
+
+    let samples: Vec<i16> = input
+        .chunks_exact(2)
+        .map(|bytes| i16::from_le_bytes([bytes[0], bytes[1]]))
+        .collect();
+
+    let mut encoder = Encoder::new();
+    let output: Vec<u8> = samples
+        .chunks(2)
+        .map(|pair| encoder.encode_pair(pair[0], pair.get(1).copied().unwrap_or(0)))
+        .collect();
+
+    std::io::stdout().write_all(&output)
+}
@@ -2,10 +2,10 @@
 #![deny(unsafe_code)]
 
 use stm32f1xx_hal::device::{I2C1, USART2};
-use stm32f1xx_hal::gpio::{Alternate, Input, Output};
+use stm32f1xx_hal::gpio::{Alternate, Analog, Input, Output};
 use stm32f1xx_hal::gpio::{Floating, OpenDrain, PullDown, PushPull};
 use stm32f1xx_hal::gpio::{
-    PA2, PA3, PA4, PA5, PA8, PA9, PB0, PB12, PB13, PB14, PB15, PB3, PB5, PB6, PB7,
+    PA0, PA2, PA3, PA4, PA5, PA8, PA9, PB0, PB12, PB13, PB14, PB15, PB3, PB5, PB6, PB7,
 };
 use stm32f1xx_hal::i2c::BlockingI2c;
 use stm32f1xx_hal::pac::SPI2;
@@ -22,6 +22,7 @@ pub type Laser = PA5<Output<PushPull>>;
 pub type LaserServoPin = PA9<Alternate<PushPull>>;
 
 pub type Led = PB3<Output<PushPull>>;
+pub type BatterySense = PA0<Analog>;
 pub type Button = PB5<Input<PullDown>>;
 
 pub type SpiCs = PB12<Output<PushPull>>;
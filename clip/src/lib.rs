@@ -0,0 +1,164 @@
+#![cfg_attr(not(test), no_std)]
+#![deny(unsafe_code)]
+
+// Clip data stream for the audio player.
+// Bytes read to look for a header can be put back if they turn out to be samples.
+// End of data is detected by reading ahead, so the player knows which buffer is
+// the last one even if the clip length is a multiple of the buffer size.
+
+// Max number of bytes put back or read ahead.
+const MAX_UNREAD: usize = 8;
+
+// Data source, e.g. a file. Reads return fewer bytes than requested only at the end.
+pub trait Source {
+    type Error;
+
+    fn read(&mut self, buffer: &mut [u8]) -> Result<usize, Self::Error>;
+}
+
+#[derive(Debug)]
+pub struct ClipData<S> {
+    source: S,
+    // Bytes taken from the source but not returned yet.
+    unread: [u8; MAX_UNREAD],
+    unread_len: usize,
+}
+
+impl<S: Source> ClipData<S> {
+    pub const fn new(source: S) -> Self {
+        ClipData {
+            source,
+            unread: [0; MAX_UNREAD],
+            unread_len: 0,
+        }
+    }
+
+    // Fill the buffer, returns fewer bytes only at the end of data.
+    pub fn read(&mut self, buffer: &mut [u8]) -> Result<usize, S::Error> {
+        let len = self.unread_len.min(buffer.len());
+        buffer[..len].copy_from_slice(&self.unread[..len]);
+        self.unread.copy_within(len..self.unread_len, 0);
+        self.unread_len -= len;
+
+        if len == buffer.len() {
+            return Ok(len);
+        }
+
+        Ok(len + self.source.read(&mut buffer[len..])?)
+    }
+
+    // Return bytes to be read again. Panics if they don't fit.
+    pub fn unread(&mut self, data: &[u8]) {
+        let len = data.len();
+        assert!(self.unread_len + len <= MAX_UNREAD);
+
+        self.unread.copy_within(..self.unread_len, len);
+        self.unread[..len].copy_from_slice(data);
+        self.unread_len += len;
+    }
+
+    // Check if all data was read, reads ahead if needed.
+    pub fn is_at_end(&mut self) -> Result<bool, S::Error> {
+        if self.unread_len == 0 {
+            self.unread_len = self.source.read(&mut self.unread[..1])?;
+        }
+
+        Ok(self.unread_len == 0)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    struct Slice<'a> {
+        data: &'a [u8],
+        reads: usize,
+    }
+
+    impl<'a> Slice<'a> {
+        fn new(data: &'a [u8]) -> Self {
+            Slice { data, reads: 0 }
+        }
+    }
+
+    impl Source for Slice<'_> {
+        type Error = ();
+
+        fn read(&mut self, buffer: &mut [u8]) -> Result<usize, ()> {
+            let len = buffer.len().min(self.data.len());
+            buffer[..len].copy_from_slice(&self.data[..len]);
+            self.data = &self.data[len..];
+            self.reads += 1;
+
+            Ok(len)
+        }
+    }
+
+    #[test]
+    fn test_read() {
+        let mut clip = ClipData::new(Slice::new(&[1, 2, 3, 4, 5]));
+        let mut buffer = [0; 4];
+
+        assert_eq!(clip.read(&mut buffer), Ok(4));
+        assert_eq!(buffer, [1, 2, 3, 4]);
+        assert_eq!(clip.is_at_end(), Ok(false));
+
+        assert_eq!(clip.read(&mut buffer), Ok(1));
+        assert_eq!(buffer[0], 5);
+        assert_eq!(clip.is_at_end(), Ok(true));
+        assert_eq!(clip.read(&mut buffer), Ok(0));
+    }
+
+    #[test]
+    fn test_end_on_buffer_boundary() {
+        // Length is a multiple of the buffer, the last full buffer is known to be last.
+        let mut clip = ClipData::new(Slice::new(&[1, 2, 3, 4, 5, 6, 7, 8]));
+        let mut buffer = [0; 4];
+
+        assert_eq!(clip.read(&mut buffer), Ok(4));
+        assert_eq!(clip.is_at_end(), Ok(false));
+
+        // Byte read ahead is returned first.
+        assert_eq!(clip.read(&mut buffer), Ok(4));
+        assert_eq!(buffer, [5, 6, 7, 8]);
+        assert_eq!(clip.is_at_end(), Ok(true));
+        assert_eq!(clip.read(&mut buffer), Ok(0));
+    }
+
+    #[test]
+    fn test_is_at_end_reads_once() {
+        let mut clip = ClipData::new(Slice::new(&[1, 2]));
+
+        assert_eq!(clip.is_at_end(), Ok(false));
+        assert_eq!(clip.is_at_end(), Ok(false));
+        assert_eq!(clip.source.reads, 1);
+    }
+
+    #[test]
+    fn test_unread() {
+        let mut clip = ClipData::new(Slice::new(&[1, 2, 3, 4, 5, 6]));
+        let mut header = [0; 4];
+
+        assert_eq!(clip.read(&mut header), Ok(4));
+        clip.unread(&header);
+
+        let mut buffer = [0; 8];
+        assert_eq!(clip.read(&mut buffer), Ok(6));
+        assert_eq!(buffer[..6], [1, 2, 3, 4, 5, 6]);
+    }
+
+    #[test]
+    fn test_unread_before_read_ahead() {
+        let mut clip = ClipData::new(Slice::new(&[1, 2, 3]));
+        let mut header = [0; 2];
+
+        assert_eq!(clip.read(&mut header), Ok(2));
+        assert_eq!(clip.is_at_end(), Ok(false));
+        clip.unread(&header);
+
+        let mut buffer = [0; 4];
+        assert_eq!(clip.read(&mut buffer), Ok(3));
+        assert_eq!(buffer[..3], [1, 2, 3]);
+    }
+}
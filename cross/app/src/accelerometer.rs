@@ -0,0 +1,95 @@
+#![deny(unsafe_code)]
+
+// Minimal driver for LIS3DH accelerometer.
+
+use embedded_hal::blocking::i2c::{Write, WriteRead};
+
+// Default address with SA0 pulled low.
+pub const ADDR: u8 = 0x18;
+
+const WHO_AM_I: u8 = 0x0F;
+const CTRL_REG1: u8 = 0x20;
+const CTRL_REG4: u8 = 0x23;
+const OUT_X_L: u8 = 0x28;
+
+const DEVICE_ID: u8 = 0x33;
+// Set MSB of register address to read multiple registers in one transaction.
+const AUTO_INCREMENT: u8 = 0x80;
+// 10 Hz data rate, normal mode, all axes enabled.
+const CTRL_REG1_VALUE: u8 = 0x27;
+// Block data update, +/-2g full scale, high resolution mode.
+const CTRL_REG4_VALUE: u8 = 0x88;
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Error<E> {
+    I2c(E),
+    InvalidDevice(u8),
+}
+
+impl<E> From<E> for Error<E> {
+    fn from(error: E) -> Self {
+        Error::I2c(error)
+    }
+}
+
+// Acceleration in milli-g.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct Acceleration {
+    pub x: i16,
+    pub y: i16,
+    pub z: i16,
+}
+
+impl Acceleration {
+    // Sum of absolute per-axis differences, in milli-g.
+    pub fn distance(&self, other: &Acceleration) -> u16 {
+        self.x
+            .abs_diff(other.x)
+            .saturating_add(self.y.abs_diff(other.y))
+            .saturating_add(self.z.abs_diff(other.z))
+    }
+}
+
+pub struct Accelerometer<I2C> {
+    i2c: I2C,
+    address: u8,
+}
+
+impl<I2C, E> Accelerometer<I2C>
+where
+    I2C: Write<Error = E> + WriteRead<Error = E>,
+{
+    pub fn new(i2c: I2C, address: u8) -> Self {
+        Accelerometer { i2c, address }
+    }
+
+    pub fn init(&mut self) -> Result<(), Error<E>> {
+        let mut id = [0];
+        self.i2c.write_read(self.address, &[WHO_AM_I], &mut id)?;
+        if id[0] != DEVICE_ID {
+            return Err(Error::InvalidDevice(id[0]));
+        }
+
+        self.i2c
+            .write(self.address, &[CTRL_REG4, CTRL_REG4_VALUE])?;
+        self.i2c
+            .write(self.address, &[CTRL_REG1, CTRL_REG1_VALUE])?;
+
+        Ok(())
+    }
+
+    pub fn read(&mut self) -> Result<Acceleration, Error<E>> {
+        let mut data = [0; 6];
+        self.i2c
+            .write_read(self.address, &[OUT_X_L | AUTO_INCREMENT], &mut data)?;
+
+        // Data is 12-bit left-aligned, 1 mg per digit.
+        let axis = |offset: usize| i16::from_le_bytes([data[offset], data[offset + 1]]) >> 4;
+
+        Ok(Acceleration {
+            x: axis(0),
+            y: axis(2),
+            z: axis(4),
+        })
+    }
+}
@@ -1,10 +1,14 @@
 use crate::board::{AudioClock, AudioDma, AudioEnable, AudioPwm, Storage};
 use crate::error::Error;
 use crate::event_queue::{Event, EventQueue};
+use crate::watchdog::{self, Subsystem};
+use adpcm::Decoder;
+use clip::{ClipData, Source};
 use core::cell::RefCell;
 use core::sync::atomic::{compiler_fence, Ordering};
 use fastrand::Rng;
 use fugit::HertzU32;
+use heapless::Vec;
 use rtt_target::rprintln;
 use simplefs::{File, FileSystem};
 use stm32f1xx_hal::device::DMA1;
@@ -16,11 +20,36 @@ pub enum Sound {
     Startup,
     BeginScan,
     TargetAcquired,
+    // Follows TargetAcquired.
+    Quip,
     ContactLost,
     ContactRestored,
     TargetLost,
-    #[allow(dead_code)]
-    PickedUp, // Sensor not on board
+    PickedUp,
+    LowBattery,
+}
+
+impl Sound {
+    // Sounds with higher priority are played first and evict lower ones from full queue.
+    fn priority(self) -> u8 {
+        match self {
+            Sound::PickedUp | Sound::LowBattery => 3,
+            Sound::TargetAcquired | Sound::TargetLost => 2,
+            Sound::Startup | Sound::BeginScan | Sound::Quip | Sound::ContactRestored => 1,
+            Sound::ContactLost => 0,
+        }
+    }
+
+    // Queued sound made obsolete by this one.
+    fn supersedes(self) -> Option<Sound> {
+        match self {
+            Sound::ContactLost => Some(Sound::ContactRestored),
+            Sound::ContactRestored => Some(Sound::ContactLost),
+            Sound::TargetAcquired => Some(Sound::TargetLost),
+            Sound::TargetLost => Some(Sound::TargetAcquired),
+            _ => None,
+        }
+    }
 }
 
 #[derive(Clone, Copy)]
@@ -52,15 +81,66 @@ impl Audio {
     pub fn play(&self, sound: Sound) {
         STATE.with(|state| state.play(sound)).unwrap();
     }
+
+    // Set volume in percent of full scale, applied from the next buffer of the playing clip.
+    pub fn set_volume(&self, percent: u8) {
+        STATE
+            .with(|state| {
+                state.volume = percent.min(MAX_VOLUME);
+                Ok(())
+            })
+            .unwrap();
+    }
+
+    pub fn volume(&self) -> u8 {
+        STATE.with(|state| Ok(state.volume)).unwrap()
+    }
+
+    // Nothing is playing or waiting to be played.
+    pub fn is_idle(&self) -> bool {
+        STATE.with(|state| Ok(state.is_idle())).unwrap()
+    }
+
+    // Post the event each time the last queued sound finishes,
+    // e.g. to play another sound right after it. None removes the event.
+    pub fn set_idle_event(&self, event: Option<&'static Event<'static>>) {
+        STATE
+            .with(|state| {
+                state.idle_event = event;
+                Ok(())
+            })
+            .unwrap();
+    }
 }
 
-#[allow(dead_code)]
-// Clips are unsigned 8 bit, 16 KHz.
+// Clips without a header are unsigned 8 bit, 16 KHz.
 pub const SOUND_FREQ: HertzU32 = HertzU32::Hz(16000);
 
+// Optional clip header: magic, sample rate in Hz (u16, big endian),
+// bits per sample, reserved byte. 8 bit samples are unsigned,
+// 16 bit ones are signed little endian, 4 bit ones are IMA ADPCM.
+const CLIP_MAGIC: &[u8] = b"CLIP";
+const CLIP_HEADER_LEN: usize = 8;
+const MAX_SAMPLE_RATE: u32 = 48000;
+
 // Sound buffer size.
 const BUF_SIZE: usize = 1024;
 
+// Max number of sounds waiting for the current clip to finish.
+const QUEUE_LEN: usize = 4;
+
+// Volume in percent.
+const MAX_VOLUME: u8 = 100;
+const DEFAULT_VOLUME: u8 = MAX_VOLUME;
+// Volume ramp at the start and end of each clip, 10 ms.
+const FADE_SAMPLES: usize = 160;
+// Unsigned samples are centered around this value.
+const SILENCE: i32 = 128;
+// Channel gain in percent of the volume. SFX are mixed over voice lines,
+// they are kept quieter to leave the voice intelligible.
+const VOICE_GAIN: u8 = 100;
+const SFX_GAIN: u8 = 70;
+
 #[derive(Clone, Copy, Debug, PartialEq, Eq)]
 enum Clip {
     SfxDeploy,
@@ -94,6 +174,155 @@ impl Clip {
     const fn file_index(self) -> usize {
         self as usize
     }
+
+    // Short effects that can be mixed over another clip.
+    fn is_sfx(self) -> bool {
+        matches!(
+            self,
+            Clip::SfxDeploy | Clip::SfxActive | Clip::SfxRetract | Clip::SfxPing | Clip::SfxAlert
+        )
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+struct ClipFormat {
+    sample_rate: HertzU32,
+    bits: u8,
+}
+
+impl ClipFormat {
+    const DEFAULT: ClipFormat = ClipFormat {
+        sample_rate: SOUND_FREQ,
+        bits: 8,
+    };
+
+    // Format from the header at the start of clip data, None if there is no header.
+    fn parse(data: &[u8]) -> Option<Result<Self, Error>> {
+        if data.len() < CLIP_HEADER_LEN || &data[..CLIP_MAGIC.len()] != CLIP_MAGIC {
+            return None;
+        }
+
+        let sample_rate = u32::from(u16::from_be_bytes([data[4], data[5]]));
+        let bits = data[6];
+        if sample_rate == 0 || sample_rate > MAX_SAMPLE_RATE || !matches!(bits, 4 | 8 | 16) {
+            rprintln!("unsupported clip: {} Hz, {} bits", sample_rate, bits);
+            return Some(Err(Error::UnsupportedClip));
+        }
+
+        Some(Ok(ClipFormat {
+            sample_rate: HertzU32::Hz(sample_rate),
+            bits,
+        }))
+    }
+
+    // Bytes of clip data holding `samples` samples.
+    fn data_len(self, samples: usize) -> usize {
+        match self.bits {
+            4 => samples.div_ceil(2),
+            8 => samples,
+            _ => samples * 2,
+        }
+    }
+
+    // Max number of samples read into a buffer at once.
+    fn buffer_samples(self) -> usize {
+        if self.bits == 16 {
+            BUF_SIZE / 2
+        } else {
+            BUF_SIZE
+        }
+    }
+}
+
+fn to_unsigned(sample: i16) -> u8 {
+    ((sample >> 8) as i32 + SILENCE) as u8
+}
+
+// Clip file as a data source.
+struct ClipFile(File<'static, Storage>);
+
+impl Source for ClipFile {
+    type Error = Error;
+
+    fn read(&mut self, buffer: &mut [u8]) -> Result<usize, Error> {
+        Ok(self.0.read(buffer)?)
+    }
+}
+
+// Clip file being played, samples are converted to unsigned 8 bit.
+struct ClipReader {
+    data: ClipData<ClipFile>,
+    format: ClipFormat,
+    // ADPCM state carries over from one read to the next.
+    decoder: Decoder,
+    // Number of samples read from the file.
+    position: usize,
+}
+
+impl ClipReader {
+    fn new(file: File<'static, Storage>) -> Result<Self, Error> {
+        let mut data = ClipData::new(ClipFile(file));
+        let mut header = [0; CLIP_HEADER_LEN];
+        let bytes_read = data.read(&mut header)?;
+        let format = match ClipFormat::parse(&header[..bytes_read]) {
+            Some(format) => format?,
+            None => {
+                // Clip without a header, these are its first samples.
+                data.unread(&header[..bytes_read]);
+                ClipFormat::DEFAULT
+            }
+        };
+
+        Ok(ClipReader {
+            data,
+            format,
+            decoder: Decoder::new(),
+            position: 0,
+        })
+    }
+
+    // Read up to `samples` samples to the start of the buffer.
+    // Returns the number of samples read and if the clip has ended.
+    fn read(
+        &mut self,
+        buffer: &mut [u8; BUF_SIZE],
+        samples: usize,
+    ) -> Result<(usize, bool), Error> {
+        let len = self.format.data_len(samples);
+
+        let samples_read = match self.format.bits {
+            4 => {
+                // Samples take twice the space of the data. Data is read to the end
+                // of the buffer, so decoding never overwrites the bytes not decoded yet.
+                let start = BUF_SIZE - len;
+                let bytes_read = self.data.read(&mut buffer[start..])?;
+                for i in 0..bytes_read {
+                    let [first, second] = self.decoder.decode_byte(buffer[start + i]);
+                    buffer[2 * i] = to_unsigned(first);
+                    buffer[2 * i + 1] = to_unsigned(second);
+                }
+
+                (bytes_read * 2).min(samples)
+            }
+            8 => self.data.read(&mut buffer[..len])?,
+            _ => {
+                let bytes_read = self.data.read(&mut buffer[..len])?;
+                for i in 0..bytes_read / 2 {
+                    let sample = i16::from_le_bytes([buffer[2 * i], buffer[2 * i + 1]]);
+                    buffer[i] = to_unsigned(sample);
+                }
+
+                bytes_read / 2
+            }
+        };
+
+        self.position += samples_read;
+        // Reading ahead finds the end of a clip that fills the last buffer exactly,
+        // so that buffer is faded out too.
+        let last = samples_read < samples || self.data.is_at_end()?;
+
+        Ok((samples_read, last))
+    }
 }
 
 const STARTUP_CLIPS: &[Clip] = &[Clip::SfxDeploy, Clip::SfxActive];
@@ -112,6 +341,7 @@ const TARGET_ACQUIRED_CLIPS: &[Clip] = &[
     Clip::ISeeYou,
     Clip::ThereYouAre,
 ];
+const QUIP_CLIPS: &[Clip] = &[Clip::Hi, Clip::WhoAreYou];
 const CONTACT_LOST_CLIPS: &[Clip] = &[Clip::SfxRetract];
 const CONTACT_RESTORED_CLIPS: &[Clip] = &[Clip::SfxPing, Clip::Hi, Clip::SfxAlert];
 const TARGET_LOST_CLIPS: &[Clip] = &[
@@ -120,6 +350,7 @@ const TARGET_LOST_CLIPS: &[Clip] = &[
     Clip::AreYouStillThere,
     Clip::TargetLost,
 ];
+const LOW_BATTERY_CLIPS: &[Clip] = &[Clip::Malfunctioning];
 const PICKED_UP_CLIPS: &[Clip] = &[
     Clip::Malfunctioning,
     Clip::PutMeDown,
@@ -127,10 +358,29 @@ const PICKED_UP_CLIPS: &[Clip] = &[
     Clip::PleasePutMeDown,
 ];
 
+fn sound_clips(sound: Sound) -> &'static [Clip] {
+    match sound {
+        Sound::Startup => STARTUP_CLIPS,
+        Sound::BeginScan => BEGIN_SCAN_CLIPS,
+        Sound::TargetAcquired => TARGET_ACQUIRED_CLIPS,
+        Sound::Quip => QUIP_CLIPS,
+        Sound::ContactLost => CONTACT_LOST_CLIPS,
+        Sound::ContactRestored => CONTACT_RESTORED_CLIPS,
+        Sound::TargetLost => TARGET_LOST_CLIPS,
+        Sound::PickedUp => PICKED_UP_CLIPS,
+        Sound::LowBattery => LOW_BATTERY_CLIPS,
+    }
+}
+
+// Gain applied to the volume, both in percent.
+fn channel_volume(volume: u8, gain: u8) -> u8 {
+    (u16::from(volume) * u16::from(gain) / u16::from(MAX_VOLUME)) as u8
+}
+
 enum PlayState {
     Idle,
     Playing {
-        file: File<'static, Storage>,
+        reader: ClipReader,
         next_buffer_index: usize,
         bytes_in_next_buffer: usize,
     },
@@ -146,6 +396,15 @@ struct State {
     random: Rng,
     play_state: PlayState,
     buffers: [[u8; BUF_SIZE]; 2],
+    // SFX clip mixed over the playing clip. It has the same format,
+    // so it is read in step with the playing one.
+    overlay: Option<ClipReader>,
+    overlay_buffer: [u8; BUF_SIZE],
+    volume: u8,
+    // Sounds to play next, highest priority first.
+    queue: Vec<Sound, QUEUE_LEN>,
+    // Posted when playback ends with empty queue.
+    idle_event: Option<&'static Event<'static>>,
 }
 
 impl State {
@@ -166,9 +425,18 @@ impl State {
             random,
             play_state: PlayState::Idle,
             buffers: [[0; BUF_SIZE]; 2],
+            overlay: None,
+            overlay_buffer: [0; BUF_SIZE],
+            volume: DEFAULT_VOLUME,
+            queue: Vec::new(),
+            idle_event: None,
         })
     }
 
+    fn is_idle(&self) -> bool {
+        matches!(self.play_state, PlayState::Idle) && self.queue.is_empty()
+    }
+
     fn pick_clip(&mut self, clips: &[Clip]) -> Clip {
         // TODO use random shuffle for each clip set.
         // This will provide more diverse clips for short runs.
@@ -177,41 +445,151 @@ impl State {
     }
 
     fn play(&mut self, sound: Sound) -> Result<(), Error> {
-        if !matches!(self.play_state, PlayState::Idle) {
-            rprintln!("Audio busy");
+        if matches!(self.play_state, PlayState::Idle) {
+            self.try_start_sound(sound);
             return Ok(());
         }
 
-        let clips = match sound {
-            Sound::Startup => STARTUP_CLIPS,
-            Sound::BeginScan => BEGIN_SCAN_CLIPS,
-            Sound::TargetAcquired => TARGET_ACQUIRED_CLIPS,
-            Sound::ContactLost => CONTACT_LOST_CLIPS,
-            Sound::ContactRestored => CONTACT_RESTORED_CLIPS,
-            Sound::TargetLost => TARGET_LOST_CLIPS,
-            Sound::PickedUp => PICKED_UP_CLIPS,
+        // Effects don't wait for the playing clip, they are mixed over it.
+        let clip = self.pick_clip(sound_clips(sound));
+        if !(clip.is_sfx() && self.overlay.is_none() && self.start_overlay(clip)?) {
+            self.enqueue(sound);
+        }
+
+        Ok(())
+    }
+
+    // Returns false if the clip can't be mixed with the playing one.
+    fn start_overlay(&mut self, clip: Clip) -> Result<bool, Error> {
+        let PlayState::Playing { ref reader, .. } = self.play_state else {
+            return Ok(false);
         };
-        let clip = self.pick_clip(clips);
+        let format = reader.format;
+
+        let file = self.fs.open(clip.file_index())?;
+        // Filesystem is never unmounted, so it is safe to get static reference.
+        let overlay = ClipReader::new(unsafe { core::mem::transmute(file) })?;
+
+        if overlay.format != format {
+            rprintln!("can't mix {:?}, format differs", clip);
+            return Ok(false);
+        }
+
+        rprintln!("mixing {:?}", clip);
+        self.overlay = Some(overlay);
+
+        Ok(true)
+    }
+
+    // Read the overlay in step with the playing clip and add it to the samples.
+    fn mix_overlay(&mut self, buffer_index: usize, samples: usize) -> Result<(), Error> {
+        let Some(overlay) = &mut self.overlay else {
+            return Ok(());
+        };
+
+        let position = overlay.position;
+        let (mixed, last) = overlay.read(&mut self.overlay_buffer, samples)?;
+
+        Self::scale_samples(
+            &mut self.overlay_buffer[..mixed],
+            channel_volume(self.volume, SFX_GAIN),
+            position,
+            last,
+        );
+
+        let buffer = &mut self.buffers[buffer_index][..mixed];
+        for (sample, &overlay_sample) in buffer.iter_mut().zip(self.overlay_buffer.iter()) {
+            let sum = i32::from(*sample) + i32::from(overlay_sample) - SILENCE;
+            *sample = sum.clamp(0, u8::MAX.into()) as u8;
+        }
+
+        if last {
+            self.overlay = None;
+        }
+
+        Ok(())
+    }
+
+    fn enqueue(&mut self, sound: Sound) {
+        // Coalesce repeated sounds and drop the ones that are no longer relevant.
+        self.queue
+            .retain(|&queued| queued != sound && Some(queued) != sound.supersedes());
+
+        if self.queue.is_full() {
+            // Queue is sorted, the last one has the lowest priority.
+            match self.queue.last() {
+                Some(last) if last.priority() < sound.priority() => {
+                    rprintln!("Audio queue full, dropping {:?}", last);
+                    self.queue.pop();
+                }
+                _ => {
+                    rprintln!("Audio queue full, dropping {:?}", sound);
+                    return;
+                }
+            }
+        }
+
+        let index = self
+            .queue
+            .iter()
+            .position(|queued| queued.priority() < sound.priority())
+            .unwrap_or(self.queue.len());
+        // Can't fail, there is free space.
+        self.queue.insert(index, sound).unwrap();
+    }
+
+    // Start the next queued sound, post the idle event if there is none.
+    fn play_queued(&mut self) {
+        while matches!(self.play_state, PlayState::Idle) && !self.queue.is_empty() {
+            let sound = self.queue.remove(0);
+            self.try_start_sound(sound);
+        }
+
+        if self.is_idle() {
+            if let Some(event) = self.idle_event {
+                event.call();
+            }
+        }
+    }
+
+    // Sound that fails to start is dropped.
+    fn try_start_sound(&mut self, sound: Sound) {
+        if let Err(err) = self.start_sound(sound) {
+            rprintln!("can't play {:?}: {:?}", sound, err);
+        }
+    }
+
+    fn start_sound(&mut self, sound: Sound) -> Result<(), Error> {
+        let clip = self.pick_clip(sound_clips(sound));
 
         rprintln!("playing {:?}", clip);
 
-        let mut file = self.fs.open(clip.file_index())?;
-        let bytes_read = file.read(&mut self.buffers[0])?;
+        let file = self.fs.open(clip.file_index())?;
+        // Filesystem is never unmounted, so it is safe to get static reference.
+        let mut reader = ClipReader::new(unsafe { core::mem::transmute(file) })?;
 
-        if bytes_read == 0 {
+        let (samples, last) = reader.read(&mut self.buffers[0], reader.format.buffer_samples())?;
+        if samples == 0 {
             rprintln!("Clip data is empty");
             return Ok(());
         }
 
+        Self::scale_samples(
+            &mut self.buffers[0][0..samples],
+            channel_volume(self.volume, VOICE_GAIN),
+            0,
+            last,
+        );
+
+        let sample_rate = reader.format.sample_rate;
         self.play_state = PlayState::Playing {
-            // Filesystem is never unmounted, so it is safe to get static reference.
-            file: unsafe { core::mem::transmute(file) },
+            reader,
             next_buffer_index: 0,
-            bytes_in_next_buffer: bytes_read,
+            bytes_in_next_buffer: samples,
         };
 
         {
-            self.start_playback()?;
+            self.start_playback(sample_rate)?;
             self.play_next_buffer()
         }
         .map_err(|err| {
@@ -224,7 +602,25 @@ impl State {
         Ok(())
     }
 
+    // DMA posts the refill event, so errors stop the clip instead of failing
+    // the event: a retry would replay a stale buffer.
+    fn refill(&mut self) {
+        let Err(err) = self.play_next_buffer() else {
+            return;
+        };
+        rprintln!("playback failed: {:?}", err);
+
+        if !matches!(self.play_state, PlayState::Idle) {
+            if let Err(err) = self.end_playback() {
+                rprintln!("can't stop playback: {:?}", err);
+            }
+        }
+        self.play_queued();
+    }
+
     fn play_next_buffer(&mut self) -> Result<(), Error> {
+        watchdog::heartbeat(Subsystem::Audio);
+
         let state = &mut self.play_state;
         match state {
             PlayState::Idle => {
@@ -232,7 +628,7 @@ impl State {
                 rprintln!("play_next_block called in Idle state");
             }
             PlayState::Playing {
-                file,
+                reader,
                 next_buffer_index,
                 bytes_in_next_buffer,
             } => {
@@ -246,23 +642,58 @@ impl State {
                 )?;
 
                 // Read more data
-                *bytes_in_next_buffer = file.read(&mut self.buffers[*next_buffer_index])?;
-                if *bytes_in_next_buffer == 0 {
+                let buffer = &mut self.buffers[*next_buffer_index];
+                let position = reader.position;
+                let (samples, last) = reader.read(buffer, reader.format.buffer_samples())?;
+                *bytes_in_next_buffer = samples;
+                if samples == 0 {
                     self.play_state = PlayState::LastBlock;
+                } else {
+                    Self::scale_samples(
+                        &mut buffer[0..samples],
+                        channel_volume(self.volume, VOICE_GAIN),
+                        position,
+                        last,
+                    );
+
+                    let (buffer_index, samples) = (*next_buffer_index, *bytes_in_next_buffer);
+                    self.mix_overlay(buffer_index, samples)?;
                 }
             }
             PlayState::LastBlock => {
                 self.end_playback()?;
+                self.play_queued();
             }
         }
 
         Ok(())
     }
 
-    fn start_playback(&mut self) -> Result<(), Error> {
+    // Apply volume and fade to samples starting at `position` in the clip.
+    // Last buffer of the clip is faded out to avoid a click.
+    fn scale_samples(buffer: &mut [u8], volume: u8, position: usize, last: bool) {
+        let len = buffer.len();
+
+        for (i, sample) in buffer.iter_mut().enumerate() {
+            let fade_in = (position + i).min(FADE_SAMPLES);
+            let fade_out = if last {
+                (len - 1 - i).min(FADE_SAMPLES)
+            } else {
+                FADE_SAMPLES
+            };
+            let gain = i32::from(volume) * fade_in.min(fade_out) as i32;
+
+            let value = i32::from(*sample) - SILENCE;
+            let scaled = value * gain / (i32::from(MAX_VOLUME) * FADE_SAMPLES as i32);
+            *sample = (scaled + SILENCE) as u8;
+        }
+    }
+
+    fn start_playback(&mut self, sample_rate: HertzU32) -> Result<(), Error> {
         self.audio_enable.set_high();
         self.audio_pwm.enable(Channel::C3);
-        self.audio_clock.start(SOUND_FREQ)?;
+        self.audio_clock.start(sample_rate)?;
+        watchdog::set_active(Subsystem::Audio, true);
 
         Ok(())
     }
@@ -284,11 +715,14 @@ impl State {
         debug_assert!(!matches!(self.play_state, PlayState::Idle));
 
         self.play_state = PlayState::Idle;
+        // Overlay is cut off with the clip it is mixed over.
+        self.overlay = None;
 
         self.audio_enable.set_low();
         self.audio_pwm.disable(Channel::C3);
         self.audio_pwm.set_duty(Channel::C3, 0);
         self.audio_clock.cancel()?;
+        watchdog::set_active(Subsystem::Audio, false);
 
         Ok(())
     }
@@ -326,8 +760,14 @@ unsafe impl Sync for StaticState {}
 
 static STATE: StaticState = StaticState::new();
 
-static PLAY_NEXT_BUFFER: Event =
-    Event::new(&|| STATE.with(|state| state.play_next_buffer()).unwrap());
+// DMA plays the other buffer meanwhile, refill it before slower handlers run.
+static PLAY_NEXT_BUFFER: Event = Event::new_fallible(&|| {
+    STATE.with(|state| {
+        state.refill();
+        Ok(())
+    })
+})
+.with_priority(1);
 
 #[interrupt]
 unsafe fn DMA1_CHANNEL2() {
@@ -1,38 +1,49 @@
+use crate::accelerometer;
 use crate::error::Error;
 use crate::storage::SoundStorage;
-use crate::system_time::Ticker;
+use crate::system_time::{Duration, Ticker};
 
 use fastrand::Rng;
 use fugit::TimerDurationU32;
 use num::rational::Ratio;
 use rtt_target::rprintln;
 use servo::{Bounds, Servo};
-use stm32f1xx_hal::adc::Adc;
-use stm32f1xx_hal::device::{TIM1, TIM3};
+use stm32f1xx_hal::device::{ADC1, TIM1, TIM3};
 use stm32f1xx_hal::dma::dma1;
+use stm32f1xx_hal::gpio::{Edge, ExtiPin};
 use stm32f1xx_hal::i2c::{I2c, Mode};
 use stm32f1xx_hal::pac;
 use stm32f1xx_hal::prelude::*;
 use stm32f1xx_hal::spi::Spi;
 use stm32f1xx_hal::time::{Hertz, MilliSeconds};
-use stm32f1xx_hal::timer::{Ch, CounterHz, Pwm, PwmChannel, Tim3NoRemap, Timer};
+use stm32f1xx_hal::timer::{Ch, CounterHz, Pwm, PwmChannel, Tim3NoRemap};
+use stm32f1xx_hal::watchdog::IndependentWatchdog;
 use vl53l1x::{BootState, VL53L1X};
 
-pub use board::{AudioEnable, Laser, Led, SpiBus, SpiCs};
+pub use board::{AudioEnable, BatterySense, Button, Laser, Led, SpiBus, SpiCs};
 
 const SERVO_FREQ: Hertz = Hertz::Hz(50);
 // Set max available clock frequency.
 // Not important for CPU but audio PWM resolution is barely enough even this way.
 // In hindsight, should have used chip with DAC.
 const CLOCK_FREQ: u32 = 64_000_000;
-
-pub type Sensor = VL53L1X<board::I2cBus>;
+// Sensor boots in 1.2 ms according to the datasheet.
+const SENSOR_BOOT_TIMEOUT: Duration = Duration::millis(100);
+// Twice the health check interval in watchdog.rs.
+const WATCHDOG_TIMEOUT: MilliSeconds = MilliSeconds::secs(4);
+
+pub type I2cProxy = shared_bus::I2cProxy<'static, shared_bus::CortexMMutex<board::I2cBus>>;
+pub type Sensor = VL53L1X<I2cProxy>;
+pub type Accelerometer = accelerometer::Accelerometer<I2cProxy>;
 pub type SensorServo = Servo<PwmChannel<TIM1, 0>>;
 pub type LaserServo = Servo<PwmChannel<TIM1, 1>>;
 pub type Storage = SoundStorage;
+pub type Crc = stm32f1xx_hal::crc::Crc;
+pub type Adc = stm32f1xx_hal::adc::Adc<ADC1>;
 pub type AudioDma = dma1::C2;
 pub type AudioPwm = Pwm<TIM3, Tim3NoRemap, Ch<2>, board::AudioPwmPin, CLOCK_FREQ>;
 pub type AudioClock = CounterHz<stm32f1xx_hal::pac::TIM2>;
+pub type Watchdog = IndependentWatchdog;
 
 pub struct Board {
     pub ticker: Ticker,
@@ -40,15 +51,20 @@ pub struct Board {
     pub laser_servo: LaserServo,
     pub sensor: Sensor,
     pub sensor_servo: SensorServo,
+    pub accelerometer: Option<Accelerometer>,
     pub target_lock_led: Led,
     pub button: board::Button,
     pub adc_ratio: Ratio<u16>,
+    pub adc: Adc,
+    pub battery_sense: BatterySense,
     pub storage: Storage,
+    pub crc: Crc,
     pub audio_enable: AudioEnable,
     pub audio_dma: AudioDma,
     pub audio_pwm: AudioPwm,
     pub audio_clock: AudioClock,
     pub random: Rng,
+    pub watchdog: Watchdog,
 }
 
 impl Board {
@@ -57,7 +73,9 @@ impl Board {
         dp.DBGMCU.cr.modify(|_, w| {
             w.dbg_sleep().set_bit();
             w.dbg_standby().set_bit();
-            w.dbg_stop().set_bit()
+            w.dbg_stop().set_bit();
+            // Don't reset while halted in debugger
+            w.dbg_iwdg_stop().set_bit()
         });
         dp.RCC.ahbenr.modify(|_, w| w.dma1en().enabled());
 
@@ -77,10 +95,10 @@ impl Board {
 
         // Read servo range calibration value
         let mut adc = Adc::adc1(dp.ADC1, clocks);
+        let battery_sense = gpioa.pa0.into_analog(&mut gpioa.crl);
         let mut servo_range_ch = gpioa.pa1.into_analog(&mut gpioa.crl);
         let adc_reading: u16 = adc.read(&mut servo_range_ch)?;
         let adc_max = adc.max_sample();
-        adc.release(); // No longer needed
 
         rprintln!("range {} of {}", adc_reading, adc_max);
         // Avoid too small range
@@ -91,7 +109,7 @@ impl Board {
         let (_, pb3, _) = afio.mapr.disable_jtag(gpioa.pa15, gpiob.pb3, gpiob.pb4);
 
         let target_lock_led = pb3.into_push_pull_output(&mut gpiob.crl);
-        let button = gpiob.pb5.into_pull_down_input(&mut gpiob.crl);
+        let mut button = gpiob.pb5.into_pull_down_input(&mut gpiob.crl);
         let laser_led = gpioa.pa5.into_push_pull_output(&mut gpioa.crl);
 
         let sensor_servo_pin: board::SensorServoPin =
@@ -125,7 +143,7 @@ impl Board {
         let mut laser_servo = Servo::new(laser_servo_pwm, bounds);
         laser_servo.enable();
 
-        let ticker = Ticker::new(Timer::syst(cp.SYST, &clocks));
+        let ticker = Ticker::new(cp.SYST, &clocks);
 
         let spi_cs = gpiob.pb12.into_push_pull_output(&mut gpiob.crh);
         let spi_clk = gpiob.pb13.into_alternate_push_pull(&mut gpiob.crh);
@@ -141,6 +159,7 @@ impl Board {
         );
 
         let storage = SoundStorage::new(spi, spi_cs)?;
+        let crc = dp.CRC.new();
         let audio_enable = gpioa.pa4.into_push_pull_output(&mut gpioa.crl);
 
         let scl = gpiob.pb6.into_alternate_open_drain(&mut gpiob.crl);
@@ -154,13 +173,30 @@ impl Board {
         )
         .blocking_default(clocks);
 
-        let mut sensor = VL53L1X::new(i2c, vl53l1x::ADDR);
+        // Board::new() takes the peripherals, so the bus can't be created twice.
+        let i2c_bus = shared_bus::new_cortexm!(board::I2cBus = i2c).unwrap();
+
+        let mut sensor = VL53L1X::new(i2c_bus.acquire_i2c(), vl53l1x::ADDR);
+        let boot_deadline = ticker.now() + SENSOR_BOOT_TIMEOUT;
         while sensor.boot_state()? != BootState::Booted {
+            if ticker.now() >= boot_deadline {
+                return Err(Error::Timeout);
+            }
             // Wait 10 ms until next timer tick.
             ticker.wait_for_tick();
         }
         sensor.sensor_init()?;
 
+        // Accelerometer is optional, turret works without pickup detection.
+        let mut accelerometer = Accelerometer::new(i2c_bus.acquire_i2c(), accelerometer::ADDR);
+        let accelerometer = match accelerometer.init() {
+            Ok(()) => Some(accelerometer),
+            Err(err) => {
+                rprintln!("accelerometer not available: {:?}", err);
+                None
+            }
+        };
+
         // Audio hardware setup
         // Setup TIM3 as PWM for audio output
         let audio_pin: board::AudioPwmPin = gpiob.pb0.into_alternate_push_pull(&mut gpiob.crl);
@@ -199,24 +235,43 @@ impl Board {
             cortex_m::peripheral::NVIC::unmask(pac::Interrupt::DMA1_CHANNEL2);
         }
 
+        // Button interrupt on both press and release, handled in button.rs.
+        let mut exti = dp.EXTI;
+        button.make_interrupt_source(&mut afio);
+        button.trigger_on_edge(&mut exti, Edge::RisingFalling);
+        button.enable_interrupt(&mut exti);
+
+        unsafe {
+            cortex_m::peripheral::NVIC::unmask(pac::Interrupt::EXTI9_5);
+        }
+
         // Scoop some randomish data for PRNG
         let random = Rng::with_seed(adc_reading as u64 | cp.DWT.cyccnt.read() as u64);
 
+        // Started last, slow peripheral init above can't trigger a reset.
+        let mut watchdog = IndependentWatchdog::new(dp.IWDG);
+        watchdog.start(WATCHDOG_TIMEOUT);
+
         Ok(Board {
             ticker,
             laser_led,
             laser_servo,
             sensor,
             sensor_servo,
+            accelerometer,
             target_lock_led,
             button,
             adc_ratio,
+            adc,
+            battery_sense,
             storage,
+            crc,
             audio_enable,
             audio_dma,
             audio_pwm,
             audio_clock,
             random,
+            watchdog,
         })
     }
 }
@@ -0,0 +1,207 @@
+// Button handling. EXTI interrupt on both edges posts an event, the level is read
+// once it stays stable for the debounce time.
+// Short press mutes and unmutes audio, long press toggles standby,
+// double press reboots into the hardware self-test.
+// While pressed, the button also keeps the laser off.
+
+use crate::audio::Audio;
+use crate::board::Button;
+use crate::error::Error;
+use crate::event_queue::{Event, EventQueue, ExtEvent};
+use crate::ranging;
+use crate::selftest;
+use crate::system_time::{Duration, Ticker};
+use crate::targeting::{PauseReason, Targeting};
+
+use core::cell::RefCell;
+use rtt_target::rprintln;
+use stm32f1xx_hal::device::EXTI;
+use stm32f1xx_hal::pac::interrupt;
+
+const DEBOUNCE_TIME: Duration = Duration::millis(30);
+const LONG_PRESS_TIME: Duration = Duration::millis(1000);
+// Max time between release and the second press of a double press.
+const DOUBLE_PRESS_TIME: Duration = Duration::millis(400);
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+enum Press {
+    Idle,
+    Pressed { second: bool },
+    // Long press action is done, waiting for release.
+    Held,
+    // Released after a short press, waiting for another one.
+    Released,
+}
+
+struct State {
+    ticker: Ticker,
+    button: Button,
+    targeting: Targeting,
+    audio: Audio,
+    pressed: bool,
+    press: Press,
+    // Volume before muting.
+    muted_volume: Option<u8>,
+    standby: bool,
+}
+
+impl State {
+    fn edge(&mut self) {
+        // Every bounce postpones the check.
+        BUTTON_SETTLED.call_at(self.ticker.now() + DEBOUNCE_TIME);
+    }
+
+    fn settled(&mut self) -> Result<(), Error> {
+        let pressed = self.button.is_high();
+        if pressed == self.pressed {
+            return Ok(());
+        }
+
+        self.pressed = pressed;
+        self.targeting.set_button_held(pressed)?;
+
+        self.press = match (self.press, pressed) {
+            (Press::Idle, true) => self.wait(LONG_PRESS_TIME, Press::Pressed { second: false }),
+            (Press::Released, true) => self.wait(LONG_PRESS_TIME, Press::Pressed { second: true }),
+            (Press::Pressed { second: false }, false) => {
+                self.wait(DOUBLE_PRESS_TIME, Press::Released)
+            }
+            (Press::Pressed { second: true }, false) => self.double_press(),
+            (_, false) => Press::Idle,
+            (press, true) => press,
+        };
+
+        Ok(())
+    }
+
+    fn timeout(&mut self) -> Result<(), Error> {
+        self.press = match self.press {
+            Press::Pressed { .. } => {
+                self.long_press()?;
+                Press::Held
+            }
+            Press::Released => {
+                self.short_press();
+                Press::Idle
+            }
+            press => press,
+        };
+
+        Ok(())
+    }
+
+    fn wait(&self, duration: Duration, press: Press) -> Press {
+        BUTTON_TIMEOUT.call_at(self.ticker.now() + duration);
+        press
+    }
+
+    fn short_press(&mut self) {
+        match self.muted_volume.take() {
+            Some(volume) => {
+                rprintln!("unmuted");
+                self.audio.set_volume(volume);
+            }
+            None => {
+                rprintln!("muted");
+                self.muted_volume = Some(self.audio.volume());
+                self.audio.set_volume(0);
+            }
+        }
+    }
+
+    fn long_press(&mut self) -> Result<(), Error> {
+        self.standby = !self.standby;
+
+        if self.standby {
+            rprintln!("standby");
+            ranging::pause()?;
+            self.targeting.pause(PauseReason::Standby)
+        } else {
+            rprintln!("resuming");
+            self.targeting.resume(PauseReason::Standby)?;
+            ranging::resume()
+        }
+    }
+
+    fn double_press(&mut self) -> ! {
+        rprintln!("rebooting into self-test");
+        selftest::request()
+    }
+}
+
+struct StaticState {
+    state: RefCell<Option<State>>,
+}
+
+impl StaticState {
+    const fn new() -> Self {
+        Self {
+            state: RefCell::new(None),
+        }
+    }
+
+    fn set(&self, state: State) {
+        *self.state.borrow_mut() = Some(state);
+    }
+
+    fn with<F, R>(&self, f: F) -> Result<R, Error>
+    where
+        F: Fn(&mut State) -> Result<R, Error>,
+    {
+        let mut stref = self.state.borrow_mut();
+        let state = stref.as_mut().ok_or(Error::Uninitialized)?;
+
+        f(state)
+    }
+}
+
+// STATE is only accessed from the main thread via EventQueue.
+// Therefore, no locking is necessary.
+unsafe impl Sync for StaticState {}
+
+static STATE: StaticState = StaticState::new();
+
+static BUTTON_EDGE: Event = Event::new_fallible(&|| {
+    STATE.with(|state| {
+        state.edge();
+        Ok(())
+    })
+});
+static BUTTON_SETTLED: Event = Event::new_fallible(&|| STATE.with(|state| state.settled()));
+static BUTTON_TIMEOUT: Event = Event::new_fallible(&|| STATE.with(|state| state.timeout()));
+
+pub fn start(
+    ticker: Ticker,
+    event_queue: &mut EventQueue<'_, 'static>,
+    button: Button,
+    targeting: Targeting,
+    audio: Audio,
+) -> Result<(), Error> {
+    event_queue.bind(&BUTTON_EDGE);
+    event_queue.bind(&BUTTON_SETTLED);
+    event_queue.bind(&BUTTON_TIMEOUT);
+
+    let pressed = button.is_high();
+    targeting.set_button_held(pressed)?;
+
+    STATE.set(State {
+        ticker,
+        button,
+        targeting,
+        audio,
+        pressed,
+        // Button held since boot, e.g. for self-test, is ignored until released.
+        press: if pressed { Press::Held } else { Press::Idle },
+        muted_volume: None,
+        standby: false,
+    });
+
+    Ok(())
+}
+
+#[interrupt]
+unsafe fn EXTI9_5() {
+    BUTTON_EDGE.call();
+    // Clear interrupt flag, the button is the only source on these lines.
+    (*EXTI::ptr()).pr.write(|w| w.pr5().set_bit());
+}
@@ -0,0 +1,396 @@
+// Settings persisted in a dedicated flash sector.
+// Layout: magic, version, settings fields, big endian, followed by CRC of all of them.
+// Ranging baseline is kept the same way in its own sector, so that saving settings
+// doesn't wear it and vice versa.
+
+use crate::board::{Crc, Storage};
+use crate::error::Error;
+use crate::ranging::MAX_STEPS;
+use crate::storage::{BASELINE_ADDRESS, CONFIG_ADDRESS};
+
+use core::cell::RefCell;
+use num::rational::Ratio;
+use rtt_target::rprintln;
+
+// "CONF"
+const MAGIC: u32 = 0x434f_4e46;
+// Bump when the layout changes, older settings are replaced with defaults.
+const VERSION: u16 = 5;
+
+const SETTINGS_LEN: usize = 24;
+const RECORD_LEN: usize = SETTINGS_LEN + 4;
+
+// "BASE"
+const BASELINE_MAGIC: u32 = 0x4241_5345;
+const BASELINE_VERSION: u16 = 1;
+
+// Magic, version, number of steps, servo range ADC ratio, then thresholds
+// of all steps for both directions.
+const BASELINE_HEADER_LEN: usize = 12;
+const BASELINE_LEN: usize = BASELINE_HEADER_LEN + 2 * 2 * MAX_STEPS;
+const BASELINE_RECORD_LEN: usize = BASELINE_LEN + 4;
+
+// Servo range reading is a bit noisy, baseline is still valid
+// if it differs by this many percent.
+const ADC_RATIO_TOLERANCE: u64 = 1;
+
+// Ranging thresholds for each direction and step.
+pub type Thresholds = [[u16; MAX_STEPS]; 2];
+
+// How targeting picks one of several targets.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum SelectionPolicy {
+    // Target with the smallest distance.
+    Nearest = 0,
+    // Target covering the most steps.
+    Widest = 1,
+    // Target seen in the most consecutive sweeps.
+    MostPersistent = 2,
+}
+
+impl TryFrom<u8> for SelectionPolicy {
+    type Error = ();
+
+    fn try_from(value: u8) -> Result<Self, Self::Error> {
+        match value {
+            0 => Ok(SelectionPolicy::Nearest),
+            1 => Ok(SelectionPolicy::Widest),
+            2 => Ok(SelectionPolicy::MostPersistent),
+            _ => Err(()),
+        }
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct Settings {
+    // Audio volume, percent.
+    pub volume: u8,
+    // Min width of contact to lock on, steps.
+    pub min_target_lock_range: u16,
+    // Max gap in contact before it is considered lost, steps.
+    pub max_target_break_range: u16,
+    // Time to keep laser on after the target disappears, seconds.
+    pub laser_off_delay: u16,
+    // Time after laser goes off until the target is considered lost, seconds.
+    pub target_lost_delay: u16,
+    // How far ahead of a moving target to aim, milliseconds.
+    pub lead_time: u16,
+    // Laser servo arc swept while there is no target, percent of full range.
+    // Zero disables patrol.
+    pub patrol_arc: u16,
+    // Sweeps of still contact without target lock before the step gets a new baseline.
+    // Zero disables recalibration.
+    pub recalibration_sweeps: u16,
+    // Target the laser prefers when there are several.
+    pub selection_policy: SelectionPolicy,
+}
+
+impl Default for Settings {
+    fn default() -> Self {
+        Settings {
+            volume: 100,
+            min_target_lock_range: 8,
+            max_target_break_range: 4,
+            laser_off_delay: 5,
+            target_lost_delay: 60,
+            lead_time: 200,
+            patrol_arc: 50,
+            recalibration_sweeps: 30,
+            selection_policy: SelectionPolicy::Nearest,
+        }
+    }
+}
+
+impl Settings {
+    fn to_bytes(self) -> [u8; SETTINGS_LEN] {
+        let mut bytes = [0; SETTINGS_LEN];
+        bytes[0..4].copy_from_slice(&MAGIC.to_be_bytes());
+        bytes[4..6].copy_from_slice(&VERSION.to_be_bytes());
+        bytes[6] = self.volume;
+        bytes[7] = self.selection_policy as u8;
+        bytes[8..10].copy_from_slice(&self.min_target_lock_range.to_be_bytes());
+        bytes[10..12].copy_from_slice(&self.max_target_break_range.to_be_bytes());
+        bytes[12..14].copy_from_slice(&self.laser_off_delay.to_be_bytes());
+        bytes[14..16].copy_from_slice(&self.target_lost_delay.to_be_bytes());
+        bytes[16..18].copy_from_slice(&self.lead_time.to_be_bytes());
+        bytes[18..20].copy_from_slice(&self.patrol_arc.to_be_bytes());
+        bytes[20..22].copy_from_slice(&self.recalibration_sweeps.to_be_bytes());
+        // Bytes 22..24 are reserved.
+
+        bytes
+    }
+
+    fn from_bytes(bytes: &[u8; SETTINGS_LEN]) -> Option<Self> {
+        let u16_at = |offset: usize| u16::from_be_bytes([bytes[offset], bytes[offset + 1]]);
+
+        if bytes[0..4] != MAGIC.to_be_bytes() || u16_at(4) != VERSION {
+            return None;
+        }
+
+        Some(Settings {
+            volume: bytes[6],
+            min_target_lock_range: u16_at(8),
+            max_target_break_range: u16_at(10),
+            laser_off_delay: u16_at(12),
+            target_lost_delay: u16_at(14),
+            lead_time: u16_at(16),
+            patrol_arc: u16_at(18),
+            recalibration_sweeps: u16_at(20),
+            selection_policy: SelectionPolicy::try_from(bytes[7]).ok()?,
+        })
+    }
+}
+
+fn baseline_to_bytes(
+    adc_ratio: Ratio<u16>,
+    total_steps: u16,
+    thresholds: &Thresholds,
+) -> [u8; BASELINE_LEN] {
+    let mut bytes = [0; BASELINE_LEN];
+    bytes[0..4].copy_from_slice(&BASELINE_MAGIC.to_be_bytes());
+    bytes[4..6].copy_from_slice(&BASELINE_VERSION.to_be_bytes());
+    bytes[6..8].copy_from_slice(&total_steps.to_be_bytes());
+    bytes[8..10].copy_from_slice(&adc_ratio.numer().to_be_bytes());
+    bytes[10..12].copy_from_slice(&adc_ratio.denom().to_be_bytes());
+
+    let values = thresholds.iter().flatten();
+    for (chunk, value) in bytes[BASELINE_HEADER_LEN..].chunks_exact_mut(2).zip(values) {
+        chunk.copy_from_slice(&value.to_be_bytes());
+    }
+
+    bytes
+}
+
+// Returns thresholds only if they were measured for the same servo range.
+fn baseline_from_bytes(
+    bytes: &[u8; BASELINE_LEN],
+    adc_ratio: Ratio<u16>,
+    total_steps: u16,
+) -> Option<Thresholds> {
+    let u16_at = |offset: usize| u16::from_be_bytes([bytes[offset], bytes[offset + 1]]);
+
+    if bytes[0..4] != BASELINE_MAGIC.to_be_bytes() || u16_at(4) != BASELINE_VERSION {
+        return None;
+    }
+
+    if u16_at(6) != total_steps || u16_at(10) == 0 {
+        return None;
+    }
+
+    let saved_ratio = Ratio::new(u16_at(8), u16_at(10));
+    if !is_same_range(saved_ratio, adc_ratio) {
+        return None;
+    }
+
+    let mut thresholds = [[0; MAX_STEPS]; 2];
+    let values = thresholds.iter_mut().flatten();
+    for (value, chunk) in values.zip(bytes[BASELINE_HEADER_LEN..].chunks_exact(2)) {
+        *value = u16::from_be_bytes([chunk[0], chunk[1]]);
+    }
+
+    Some(thresholds)
+}
+
+fn is_same_range(a: Ratio<u16>, b: Ratio<u16>) -> bool {
+    let (a_numer, a_denom) = (*a.numer() as u64, *a.denom() as u64);
+    let (b_numer, b_denom) = (*b.numer() as u64, *b.denom() as u64);
+
+    (a_numer * b_denom).abs_diff(b_numer * a_denom) * 100 <= a_denom * b_denom * ADC_RATIO_TOLERANCE
+}
+
+struct State {
+    storage: Storage,
+    crc: Crc,
+    settings: Settings,
+}
+
+impl State {
+    fn init(storage: Storage, crc: Crc) -> Result<Self, Error> {
+        let mut state = State {
+            storage,
+            crc,
+            settings: Settings::default(),
+        };
+
+        state.load()?;
+
+        Ok(state)
+    }
+
+    fn checksum(&mut self, data: &[u8]) -> u32 {
+        self.crc.reset();
+        for word in data.chunks_exact(4) {
+            self.crc
+                .write(u32::from_be_bytes([word[0], word[1], word[2], word[3]]));
+        }
+
+        self.crc.read()
+    }
+
+    fn load(&mut self) -> Result<(), Error> {
+        let mut record = [0; RECORD_LEN];
+        self.storage.read_bytes(CONFIG_ADDRESS, &mut record)?;
+
+        let (data, crc) = record.split_at(SETTINGS_LEN);
+        let crc = u32::from_be_bytes([crc[0], crc[1], crc[2], crc[3]]);
+        let settings = if self.checksum(data) == crc {
+            // Can't fail, the slice has the right length.
+            Settings::from_bytes(data.try_into().unwrap())
+        } else {
+            None
+        };
+
+        match settings {
+            Some(settings) => {
+                rprintln!("loaded {:?}", settings);
+                self.settings = settings;
+            }
+            None => {
+                rprintln!("no valid settings, using defaults");
+                self.settings = Settings::default();
+            }
+        }
+
+        Ok(())
+    }
+
+    fn save(&mut self) -> Result<(), Error> {
+        let mut record = [0; RECORD_LEN];
+        let data = self.settings.to_bytes();
+        let crc = self.checksum(&data);
+
+        record[..SETTINGS_LEN].copy_from_slice(&data);
+        record[SETTINGS_LEN..].copy_from_slice(&crc.to_be_bytes());
+
+        self.storage.write_sector(CONFIG_ADDRESS, &mut record)?;
+        rprintln!("saved {:?}", self.settings);
+
+        Ok(())
+    }
+
+    fn load_baseline(
+        &mut self,
+        adc_ratio: Ratio<u16>,
+        total_steps: u16,
+    ) -> Result<Option<Thresholds>, Error> {
+        let mut record = [0; BASELINE_RECORD_LEN];
+        self.storage.read_bytes(BASELINE_ADDRESS, &mut record)?;
+
+        let (data, crc) = record.split_at(BASELINE_LEN);
+        let crc = u32::from_be_bytes([crc[0], crc[1], crc[2], crc[3]]);
+        if self.checksum(data) != crc {
+            rprintln!("no valid baseline");
+            return Ok(None);
+        }
+
+        // Can't fail, the slice has the right length.
+        let thresholds = baseline_from_bytes(data.try_into().unwrap(), adc_ratio, total_steps);
+        if thresholds.is_none() {
+            rprintln!("baseline is for another servo range");
+        }
+
+        Ok(thresholds)
+    }
+
+    fn save_baseline(
+        &mut self,
+        adc_ratio: Ratio<u16>,
+        total_steps: u16,
+        thresholds: &Thresholds,
+    ) -> Result<(), Error> {
+        let mut record = [0; BASELINE_RECORD_LEN];
+        let data = baseline_to_bytes(adc_ratio, total_steps, thresholds);
+        let crc = self.checksum(&data);
+
+        record[..BASELINE_LEN].copy_from_slice(&data);
+        record[BASELINE_LEN..].copy_from_slice(&crc.to_be_bytes());
+
+        self.storage.write_sector(BASELINE_ADDRESS, &mut record)?;
+        rprintln!("saved baseline");
+
+        Ok(())
+    }
+}
+
+struct StaticState {
+    state: RefCell<Option<State>>,
+}
+
+impl StaticState {
+    const fn new() -> Self {
+        Self {
+            state: RefCell::new(None),
+        }
+    }
+
+    fn set(&self, state: State) {
+        *self.state.borrow_mut() = Some(state);
+    }
+
+    fn with<F, R>(&self, f: F) -> Result<R, Error>
+    where
+        F: FnOnce(&mut State) -> Result<R, Error>,
+    {
+        let mut stref = self.state.borrow_mut();
+        let state = stref.as_mut().ok_or(Error::Uninitialized)?;
+
+        f(state)
+    }
+}
+
+// STATE is only accessed from the main thread via EventQueue.
+// Therefore, no locking is necessary.
+unsafe impl Sync for StaticState {}
+
+static STATE: StaticState = StaticState::new();
+
+#[derive(Clone, Copy)]
+pub struct Config;
+
+impl Config {
+    // Load settings from flash, falling back to defaults if they are missing or corrupted.
+    pub fn new(storage: Storage, crc: Crc) -> Result<Self, Error> {
+        STATE.set(State::init(storage, crc)?);
+
+        Ok(Config {})
+    }
+
+    // NOT interrupt-safe
+    pub fn settings(&self) -> Result<Settings, Error> {
+        STATE.with(|state| Ok(state.settings))
+    }
+
+    // Change settings in memory, call save() to keep them over reboot.
+    // NOT interrupt-safe
+    pub fn set(&self, settings: Settings) -> Result<(), Error> {
+        STATE.with(|state| {
+            state.settings = settings;
+            Ok(())
+        })
+    }
+
+    // NOT interrupt-safe
+    pub fn save(&self) -> Result<(), Error> {
+        STATE.with(|state| state.save())
+    }
+
+    // Baseline saved for the same servo range, if there is a valid one.
+    // NOT interrupt-safe
+    pub fn load_baseline(
+        &self,
+        adc_ratio: Ratio<u16>,
+        total_steps: u16,
+    ) -> Result<Option<Thresholds>, Error> {
+        STATE.with(|state| state.load_baseline(adc_ratio, total_steps))
+    }
+
+    // NOT interrupt-safe
+    pub fn save_baseline(
+        &self,
+        adc_ratio: Ratio<u16>,
+        total_steps: u16,
+        thresholds: &Thresholds,
+    ) -> Result<(), Error> {
+        STATE.with(|state| state.save_baseline(adc_ratio, total_steps, thresholds))
+    }
+}
@@ -0,0 +1,271 @@
+// Command console on RTT down channel, for tuning and diagnostics without reflashing.
+// Commands are lines of text, replies go to the regular RTT output.
+
+use crate::audio::{Audio, Sound};
+use crate::config::{Config, SelectionPolicy, Settings};
+use crate::error::Error;
+use crate::event_queue::{Event, EventQueue, ExtEvent};
+use crate::power::Power;
+use crate::ranging;
+use crate::system_time::{Duration, Ticker};
+use crate::targeting::Targeting;
+
+use core::cell::RefCell;
+use cortex_m::peripheral::DCB;
+use heapless::Vec;
+use rtt_target::{rprintln, DownChannel};
+
+// RTT has no interrupts, the channel is polled. Only a debug probe can write
+// to it, without one the console just checks for a probe once in a while.
+const POLL_INTERVAL: Duration = Duration::millis(100);
+const IDLE_POLL_INTERVAL: Duration = Duration::secs(5);
+const MAX_LINE_LEN: usize = 64;
+
+const HELP: &str = "commands:
+  get                 show settings
+  get battery         show battery voltage and level
+  set <name> <value>  change setting: volume, lock_range, break_range,
+                      laser_off_delay, target_lost_delay, lead_time,
+                      patrol_arc, recalibration_sweeps, selection_policy
+                      (0 nearest, 1 widest, 2 most persistent)
+  save                store settings in flash
+  play <sound>        play sound: startup, scan, acquired, quip, contact_lost,
+                      contact_restored, lost, picked_up, low_battery
+  baseline            show ranging baseline
+  calibrate           measure ranging baseline again
+  selftest            check the sensor";
+
+struct State {
+    ticker: Ticker,
+    channel: DownChannel,
+    config: Config,
+    targeting: Targeting,
+    audio: Audio,
+    power: Power,
+    line: Vec<u8, MAX_LINE_LEN>,
+    // Rest of the line is dropped after overflow.
+    overflow: bool,
+}
+
+impl State {
+    fn init(
+        ticker: Ticker,
+        channel: DownChannel,
+        config: Config,
+        targeting: Targeting,
+        audio: Audio,
+        power: Power,
+    ) -> Self {
+        schedule_poll(ticker);
+
+        State {
+            ticker,
+            channel,
+            config,
+            targeting,
+            audio,
+            power,
+            line: Vec::new(),
+            overflow: false,
+        }
+    }
+
+    fn poll(&mut self) -> Result<(), Error> {
+        let mut buf = [0; 16];
+
+        loop {
+            let len = self.channel.read(&mut buf);
+            if len == 0 {
+                break;
+            }
+
+            for &byte in &buf[..len] {
+                self.add_byte(byte);
+            }
+        }
+
+        schedule_poll(self.ticker);
+
+        Ok(())
+    }
+
+    fn add_byte(&mut self, byte: u8) {
+        match byte {
+            b'\r' | b'\n' => {
+                if self.overflow {
+                    rprintln!("line too long");
+                } else if !self.line.is_empty() {
+                    let line = self.line.clone();
+                    match core::str::from_utf8(&line) {
+                        Ok(command) => {
+                            if let Err(err) = self.execute(command) {
+                                rprintln!("command failed: {:?}", err);
+                            }
+                        }
+                        Err(_) => rprintln!("invalid input"),
+                    }
+                }
+
+                self.line.clear();
+                self.overflow = false;
+            }
+            _ => {
+                if self.line.push(byte).is_err() {
+                    self.overflow = true;
+                }
+            }
+        }
+    }
+
+    fn execute(&mut self, command: &str) -> Result<(), Error> {
+        let mut words = command.split_whitespace();
+
+        match (words.next(), words.next(), words.next()) {
+            (Some("get"), None, None) => rprintln!("{:?}", self.config.settings()?),
+            (Some("get"), Some("battery"), None) => rprintln!(
+                "battery {} mV, {:?}",
+                self.power.voltage()?,
+                self.power.level()?
+            ),
+            (Some("set"), Some(name), Some(value)) => self.set(name, value)?,
+            (Some("save"), None, None) => self.config.save()?,
+            (Some("play"), Some(name), None) => match parse_sound(name) {
+                Some(sound) => self.audio.play(sound),
+                None => rprintln!("unknown sound {}", name),
+            },
+            (Some("baseline"), None, None) => ranging::dump_baseline()?,
+            (Some("calibrate"), None, None) => ranging::recalibrate()?,
+            (Some("selftest"), None, None) => {
+                if ranging::self_test()? {
+                    rprintln!("sensor ok");
+                } else {
+                    rprintln!("sensor FAILED");
+                }
+            }
+            _ => rprintln!("{}", HELP),
+        }
+
+        Ok(())
+    }
+
+    fn set(&mut self, name: &str, value: &str) -> Result<(), Error> {
+        let Ok(value) = value.parse::<u16>() else {
+            rprintln!("invalid value {}", value);
+            return Ok(());
+        };
+
+        let mut settings = self.config.settings()?;
+        if !update_setting(&mut settings, name, value) {
+            rprintln!("invalid setting {} {}", name, value);
+            return Ok(());
+        }
+
+        self.config.set(settings)?;
+        self.audio.set_volume(settings.volume);
+        self.targeting.apply_settings(&settings)?;
+        ranging::apply_settings(&settings)?;
+        rprintln!("{:?}", settings);
+
+        Ok(())
+    }
+}
+
+fn schedule_poll(ticker: Ticker) {
+    let interval = if DCB::is_debugger_attached() {
+        POLL_INTERVAL
+    } else {
+        IDLE_POLL_INTERVAL
+    };
+
+    POLL_CONSOLE.call_at(ticker.now() + interval);
+}
+
+fn update_setting(settings: &mut Settings, name: &str, value: u16) -> bool {
+    match name {
+        "volume" => match u8::try_from(value) {
+            Ok(volume) if volume <= 100 => settings.volume = volume,
+            _ => return false,
+        },
+        "lock_range" => settings.min_target_lock_range = value,
+        "break_range" => settings.max_target_break_range = value,
+        "laser_off_delay" => settings.laser_off_delay = value,
+        "target_lost_delay" => settings.target_lost_delay = value,
+        "lead_time" => settings.lead_time = value,
+        "patrol_arc" if value <= 100 => settings.patrol_arc = value,
+        "recalibration_sweeps" => settings.recalibration_sweeps = value,
+        "selection_policy" => match u8::try_from(value).map(SelectionPolicy::try_from) {
+            Ok(Ok(policy)) => settings.selection_policy = policy,
+            _ => return false,
+        },
+        _ => return false,
+    }
+
+    true
+}
+
+fn parse_sound(name: &str) -> Option<Sound> {
+    let sound = match name {
+        "startup" => Sound::Startup,
+        "scan" => Sound::BeginScan,
+        "acquired" => Sound::TargetAcquired,
+        "quip" => Sound::Quip,
+        "contact_lost" => Sound::ContactLost,
+        "contact_restored" => Sound::ContactRestored,
+        "lost" => Sound::TargetLost,
+        "picked_up" => Sound::PickedUp,
+        "low_battery" => Sound::LowBattery,
+        _ => return None,
+    };
+
+    Some(sound)
+}
+
+struct StaticState {
+    state: RefCell<Option<State>>,
+}
+
+impl StaticState {
+    const fn new() -> Self {
+        Self {
+            state: RefCell::new(None),
+        }
+    }
+
+    fn set(&self, state: State) {
+        *self.state.borrow_mut() = Some(state);
+    }
+
+    fn with<F, R>(&self, f: F) -> Result<R, Error>
+    where
+        F: Fn(&mut State) -> Result<R, Error>,
+    {
+        let mut stref = self.state.borrow_mut();
+        let state = stref.as_mut().ok_or(Error::Uninitialized)?;
+
+        f(state)
+    }
+}
+
+// STATE is only accessed from the main thread via EventQueue.
+// Therefore, no locking is necessary.
+unsafe impl Sync for StaticState {}
+
+static STATE: StaticState = StaticState::new();
+
+static POLL_CONSOLE: Event = Event::new_fallible(&|| STATE.with(|state| state.poll()));
+
+pub fn start(
+    ticker: Ticker,
+    event_queue: &mut EventQueue<'_, 'static>,
+    channel: DownChannel,
+    config: Config,
+    targeting: Targeting,
+    audio: Audio,
+    power: Power,
+) {
+    event_queue.bind(&POLL_CONSOLE);
+
+    STATE.set(State::init(
+        ticker, channel, config, targeting, audio, power,
+    ));
+}
@@ -1,18 +1,23 @@
 #![deny(unsafe_code)]
 
+use crate::accelerometer;
 use crate::storage::StorageError;
 use core::num::TryFromIntError;
 
 #[derive(Debug)]
 pub enum Error {
     Servo(servo::Error),
+    Accelerometer(accelerometer::Error<stm32f1xx_hal::i2c::Error>),
     Sensor(vl53l1x::Error<stm32f1xx_hal::i2c::Error>),
     FileSystem(simplefs::Error<StorageError>),
+    Storage(StorageError),
     Timer(stm32f1xx_hal::timer::Error),
     InvalidDuration,
     InvalidScale,
+    UnsupportedClip,
     ConversionError(TryFromIntError),
     UnexpectedlyBlocks,
+    Timeout,
     Uninitialized,
 }
 
@@ -22,6 +27,12 @@ impl From<servo::Error> for Error {
     }
 }
 
+impl From<accelerometer::Error<stm32f1xx_hal::i2c::Error>> for Error {
+    fn from(accelerometer_error: accelerometer::Error<stm32f1xx_hal::i2c::Error>) -> Self {
+        Error::Accelerometer(accelerometer_error)
+    }
+}
+
 impl From<vl53l1x::Error<stm32f1xx_hal::i2c::Error>> for Error {
     fn from(sensor_error: vl53l1x::Error<stm32f1xx_hal::i2c::Error>) -> Self {
         Error::Sensor(sensor_error)
@@ -34,6 +45,12 @@ impl From<simplefs::Error<StorageError>> for Error {
     }
 }
 
+impl From<StorageError> for Error {
+    fn from(storage_error: StorageError) -> Self {
+        Error::Storage(storage_error)
+    }
+}
+
 impl From<stm32f1xx_hal::timer::Error> for Error {
     fn from(timer_error: stm32f1xx_hal::timer::Error) -> Self {
         Error::Timer(timer_error)
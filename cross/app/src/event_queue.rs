@@ -1,9 +1,15 @@
 #![deny(unsafe_code)]
 
+use crate::error::Error;
 use crate::system_time::{Duration, Instant, Ticker};
-use cortex_m::asm::wfi;
 
-pub use event_queue::Event;
+use event_queue::ErrorAction;
+use rtt_target::rprintln;
+
+// Delay before dispatching the event again after its handler failed.
+const RETRY_DELAY: Duration = Duration::secs(1);
+
+pub type Event<'h> = event_queue::Event<'h, Error>;
 
 pub trait ExtEvent {
     fn call_at(&self, instant: Instant);
@@ -21,26 +27,33 @@ impl<'h> ExtEvent for Event<'h> {
 }
 
 pub struct EventQueue<'e, 'h> {
-    queue: event_queue::EventQueue<'e, 'h>,
+    queue: event_queue::EventQueue<'e, 'h, Error>,
     ticker: Ticker,
 }
 
+// Log handler errors instead of rebooting, the failure may be transient.
+fn retry_on_error(error: Error) -> ErrorAction {
+    rprintln!("event handler failed: {:?}", error);
+
+    ErrorAction::Retry(RETRY_DELAY.ticks())
+}
+
 impl<'e, 'h> EventQueue<'e, 'h> {
     pub fn new(ticker: Ticker) -> Self {
-        EventQueue {
-            queue: event_queue::EventQueue::new(),
-            ticker,
-        }
+        let mut queue = event_queue::EventQueue::new();
+        queue.set_error_hook(&retry_on_error);
+
+        EventQueue { queue, ticker }
     }
 
     pub fn bind(&mut self, event: &'e Event<'h>) {
         self.queue.bind(event);
     }
 
-    pub fn run_forever(self) -> ! {
+    pub fn run_forever(mut self) -> ! {
         loop {
             self.queue.run_once(self.ticker.get_ticks());
-            wfi();
+            self.ticker.sleep(|ticks| self.queue.next_deadline(ticks));
         }
     }
 }
@@ -1,20 +1,30 @@
 #![no_std]
 #![no_main]
 
+mod accelerometer;
 mod audio;
 mod board;
+mod button;
+mod config;
+mod console;
 mod error;
 mod event_queue;
+mod power;
 mod ranging;
+mod selftest;
 mod storage;
 mod system_time;
+mod tamper;
 mod targeting;
+mod watchdog;
 
 use crate::audio::Audio;
 use crate::board::Board;
+use crate::config::Config;
+use crate::power::Power;
 use crate::targeting::Targeting;
 use cortex_m_rt::entry;
-use rtt_target::rtt_init_print;
+use rtt_target::{rtt_init, set_print_channel};
 use stm32f1xx_hal::pac;
 
 use panic_probe as _;
@@ -22,14 +32,37 @@ use panic_probe as _;
 
 #[entry]
 fn main() -> ! {
-    rtt_init_print!();
+    let channels = rtt_init! {
+        up: {
+            0: {
+                size: 1024,
+                name: "Terminal"
+            }
+        }
+        down: {
+            0: {
+                size: 64,
+                name: "Terminal"
+            }
+        }
+    };
+    set_print_channel(channels.up.0);
 
     let cp = pac::CorePeripherals::take().unwrap();
     let dp = pac::Peripherals::take().unwrap();
 
-    let board = Board::new(cp, dp).unwrap();
+    let mut board = Board::new(cp, dp).unwrap();
+    if selftest::is_requested(&board) {
+        selftest::run(&mut board);
+    }
+
     let mut queue = event_queue::EventQueue::new(board.ticker);
 
+    let config = Config::new(board.storage, board.crc).unwrap();
+    let settings = config.settings().unwrap();
+
+    let patrol_random = board.random.fork();
+
     let audio = Audio::new(
         &mut queue,
         board.storage,
@@ -40,6 +73,7 @@ fn main() -> ! {
         board.random,
     )
     .unwrap();
+    audio.set_volume(settings.volume);
 
     let num_steps = ranging::get_num_steps_from_angle_scale(board.adc_ratio).unwrap();
 
@@ -51,8 +85,10 @@ fn main() -> ! {
         board.laser_servo,
         num_steps as u16,
         audio,
+        patrol_random,
     )
     .unwrap();
+    targeting.apply_settings(&settings).unwrap();
 
     ranging::start(
         board.ticker,
@@ -60,10 +96,41 @@ fn main() -> ! {
         board.sensor,
         board.sensor_servo,
         num_steps,
+        board.adc_ratio,
+        targeting,
+        audio,
+        config,
+    )
+    .unwrap();
+    ranging::apply_settings(&settings).unwrap();
+
+    let power = Power::new(
+        board.ticker,
+        &mut queue,
+        board.adc,
+        board.battery_sense,
         targeting,
         audio,
     )
     .unwrap();
 
+    button::start(board.ticker, &mut queue, board.button, targeting, audio).unwrap();
+
+    if let Some(accelerometer) = board.accelerometer {
+        tamper::start(board.ticker, &mut queue, accelerometer, targeting, audio).unwrap();
+    }
+
+    watchdog::start(board.ticker, &mut queue, board.watchdog);
+
+    console::start(
+        board.ticker,
+        &mut queue,
+        channels.down.0,
+        config,
+        targeting,
+        audio,
+        power,
+    );
+
     queue.run_forever();
 }
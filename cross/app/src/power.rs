@@ -0,0 +1,178 @@
+use crate::audio::{Audio, Sound};
+use crate::board::{Adc, BatterySense};
+use crate::error::Error;
+use crate::event_queue::{Event, EventQueue, ExtEvent};
+use crate::ranging;
+use crate::system_time::{Duration, Ticker};
+use crate::targeting::Targeting;
+
+use core::cell::RefCell;
+use embedded_hal::adc::OneShot;
+use rtt_target::rprintln;
+
+const MEASURE_INTERVAL: Duration = Duration::secs(10);
+
+// ADC reference voltage, mV.
+const ADC_REFERENCE: u32 = 3300;
+// Battery is connected to ADC through 1:2 resistor divider.
+const DIVIDER_RATIO: u32 = 2;
+
+// Thresholds for 4xAA battery pack, mV.
+const LOW_BATTERY: u16 = 4400;
+const CRITICAL_BATTERY: u16 = 4000;
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord)]
+pub enum PowerLevel {
+    Normal,
+    Low,
+    Critical,
+}
+
+impl PowerLevel {
+    fn from_voltage(voltage: u16) -> Self {
+        if voltage < CRITICAL_BATTERY {
+            PowerLevel::Critical
+        } else if voltage < LOW_BATTERY {
+            PowerLevel::Low
+        } else {
+            PowerLevel::Normal
+        }
+    }
+}
+
+struct State {
+    ticker: Ticker,
+    adc: Adc,
+    sense: BatterySense,
+    targeting: Targeting,
+    audio: Audio,
+    voltage: u16,
+    level: PowerLevel,
+}
+
+impl State {
+    fn init(
+        ticker: Ticker,
+        adc: Adc,
+        sense: BatterySense,
+        targeting: Targeting,
+        audio: Audio,
+    ) -> Result<Self, Error> {
+        let mut state = State {
+            ticker,
+            adc,
+            sense,
+            targeting,
+            audio,
+            voltage: 0,
+            level: PowerLevel::Normal,
+        };
+
+        state.measure()?;
+
+        Ok(state)
+    }
+
+    fn measure(&mut self) -> Result<(), Error> {
+        let reading: u16 = self.adc.read(&mut self.sense)?;
+        let max_reading: u32 = self.adc.max_sample().into();
+
+        self.voltage =
+            (u32::from(reading) * ADC_REFERENCE * DIVIDER_RATIO / max_reading).try_into()?;
+
+        // Level only goes down: noise shouldn't re-enable the turret,
+        // and batteries can't be replaced without reset anyway.
+        let level = PowerLevel::from_voltage(self.voltage).max(self.level);
+        if level != self.level {
+            rprintln!("battery {} mV, level {:?}", self.voltage, level);
+            self.level = level;
+
+            match level {
+                PowerLevel::Normal => {}
+                PowerLevel::Low => self.audio.play(Sound::LowBattery),
+                PowerLevel::Critical => self.shutdown()?,
+            }
+        }
+
+        if level != PowerLevel::Critical {
+            MEASURE_BATTERY.call_at(self.ticker.now() + MEASURE_INTERVAL);
+        }
+
+        Ok(())
+    }
+
+    // Stop everything that draws significant current.
+    fn shutdown(&mut self) -> Result<(), Error> {
+        rprintln!("battery critical, shutting down");
+
+        ranging::stop()?;
+        self.targeting.shutdown()?;
+
+        Ok(())
+    }
+}
+
+struct StaticState {
+    state: RefCell<Option<State>>,
+}
+
+impl StaticState {
+    const fn new() -> Self {
+        Self {
+            state: RefCell::new(None),
+        }
+    }
+
+    fn set(&self, state: State) {
+        *self.state.borrow_mut() = Some(state);
+    }
+
+    fn with<F, R>(&self, f: F) -> Result<R, Error>
+    where
+        F: Fn(&mut State) -> Result<R, Error>,
+    {
+        let mut stref = self.state.borrow_mut();
+        let state = stref.as_mut().ok_or(Error::Uninitialized)?;
+
+        f(state)
+    }
+}
+
+// STATE is only accessed from the main thread via EventQueue.
+// Therefore, no locking is necessary.
+unsafe impl Sync for StaticState {}
+
+#[derive(Clone, Copy)]
+pub struct Power;
+
+impl Power {
+    pub fn new(
+        ticker: Ticker,
+        event_queue: &mut EventQueue<'_, 'static>,
+        adc: Adc,
+        sense: BatterySense,
+        targeting: Targeting,
+        audio: Audio,
+    ) -> Result<Self, Error> {
+        event_queue.bind(&MEASURE_BATTERY);
+
+        STATE.set(State::init(ticker, adc, sense, targeting, audio)?);
+
+        Ok(Power {})
+    }
+
+    // Last measured battery voltage, mV.
+    // NOT interrupt-safe
+    pub fn voltage(&self) -> Result<u16, Error> {
+        STATE.with(|state| Ok(state.voltage))
+    }
+
+    // NOT interrupt-safe
+    pub fn level(&self) -> Result<PowerLevel, Error> {
+        STATE.with(|state| Ok(state.level))
+    }
+}
+
+static STATE: StaticState = StaticState::new();
+
+static MEASURE_BATTERY: Event = Event::new_fallible(&|| STATE.with(|state| state.measure()));
@@ -1,18 +1,21 @@
 use crate::audio::{Audio, Sound};
 use crate::board::{Sensor, SensorServo};
+use crate::config::{Config, Settings, Thresholds};
 use crate::error::Error;
 use crate::event_queue::{Event, EventQueue, ExtEvent};
 use crate::system_time::{Duration, Ticker};
 use crate::targeting::Targeting;
+use crate::watchdog::{self, Subsystem};
 
 use calibration::Calibration;
 use core::cell::RefCell;
+use core::cmp::{max, min};
 use num::rational::Ratio;
 use num::{One, Zero};
 use rtt_target::rprintln;
-use vl53l1x::{DistanceMode, TimingBudget};
+use vl53l1x::{BootState, DistanceMode, TimingBudget};
 
-const MAX_STEPS: usize = 100;
+pub const MAX_STEPS: usize = 100;
 const NUM_CALIBRATION_SAMPLES: u16 = 5;
 
 const SENSOR_TIMING_BUDGET: Duration = Duration::millis(100);
@@ -20,12 +23,16 @@ const SENSOR_INTERMEASURMENT_TIME: Duration = Duration::millis(120);
 const SENSOR_RETRY_TIME: Duration = Duration::millis(10);
 const SERVO_RESET_TIME: Duration = Duration::millis(500);
 const SERVO_STEP_TIME: Duration = Duration::millis(100);
+const SERVO_JUMP_TIME: Duration = Duration::millis(300);
 
-#[derive(Clone, Copy, Debug, PartialEq, Eq)]
-enum MoveResult {
-    SameDirection,
-    ChangeDirection,
-}
+// Coarse sweeps measure every few steps to find contacts faster,
+// fine sweeps measure every step around them.
+const COARSE_STRIDE: usize = 4;
+// Extra steps scanned on each side of the coarse contacts.
+const FINE_WINDOW_MARGIN: usize = COARSE_STRIDE;
+
+// Max change of distance between sweeps for contact to be still, mm.
+const STILL_DISTANCE_TOLERANCE: u16 = 50;
 
 #[derive(Clone, Copy, Debug, PartialEq)]
 enum CalibrationResult {
@@ -33,33 +40,101 @@ enum CalibrationResult {
     Done(u16),
 }
 
+// Steps measured in a sweep. Positions are always in fine steps,
+// so they are comparable between coarse and fine sweeps.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct StepPlan {
+    // First and last step of the sweep, inclusive.
+    pub low: u16,
+    pub high: u16,
+    // Distance between measured steps.
+    pub stride: u16,
+}
+
+impl StepPlan {
+    pub const fn full(total_steps: u16) -> Self {
+        StepPlan {
+            low: 0,
+            high: total_steps - 1,
+            stride: 1,
+        }
+    }
+}
+
+// Sweep direction. Servo backlash makes distances differ slightly
+// between directions, so each one has its own baseline.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+enum Direction {
+    Up = 0,
+    Down = 1,
+}
+
 #[derive(Clone, Copy, Debug, PartialEq)]
 enum ScanMode {
-    Baseline(Calibration),
+    Baseline(Calibration, Direction),
     ScanDown,
     ScanUp,
 }
 
+impl ScanMode {
+    fn direction(&self) -> Direction {
+        match self {
+            ScanMode::Baseline(_, direction) => *direction,
+            ScanMode::ScanDown => Direction::Down,
+            ScanMode::ScanUp => Direction::Up,
+        }
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+enum RunState {
+    Running,
+    // Standby, scanning continues from the same step on resume.
+    Paused,
+    // Low battery, can't be resumed.
+    Stopped,
+}
+
 struct Ranging {
     targeting: Targeting,
     audio: Audio,
+    config: Config,
+    // Servo range the baseline is measured for.
+    adc_ratio: Ratio<u16>,
     ticker: Ticker,
     sensor: Sensor,
     servo: SensorServo,
     mode: ScanMode,
     current_step: usize,
     total_steps: usize,
-    baseline: [u16; MAX_STEPS],
+    // Thresholds for each direction.
+    baseline: Thresholds,
+    // Distance seen at each step in the previous sweep.
+    last_distance: [u16; MAX_STEPS],
+    // Number of sweeps each step had still contact.
+    still_sweeps: [u16; MAX_STEPS],
+    // Contact at the same distance for this many sweeps is considered
+    // a permanent scene change, e.g. moved furniture. People don't stand that still.
+    recalibration_sweeps: u16,
+    // Baseline of the current step is being measured again.
+    recalibration: Option<Calibration>,
+    plan: StepPlan,
+    // Lowest and highest step with contact in the current coarse sweep.
+    candidates: Option<(usize, usize)>,
+    run_state: RunState,
 }
 
 impl Ranging {
+    #[allow(clippy::too_many_arguments)]
     fn init(
         ticker: Ticker,
         mut sensor: Sensor,
         mut servo: SensorServo,
         total_steps: usize,
+        adc_ratio: Ratio<u16>,
         targeting: Targeting,
         audio: Audio,
+        config: Config,
     ) -> Result<Self, Error> {
         sensor.set_timing_budget(TimingBudget::Ms100)?;
         sensor.set_distance_mode(DistanceMode::Long)?;
@@ -70,16 +145,46 @@ impl Ranging {
 
         audio.play(Sound::Startup);
 
+        // Calibration takes a while, skip it if the turret wasn't adjusted.
+        let saved = config.load_baseline(adc_ratio, total_steps as u16)?;
+        let (mode, baseline, plan) = match saved {
+            Some(baseline) => {
+                rprintln!("using saved baseline");
+                let plan = StepPlan {
+                    stride: COARSE_STRIDE as u16,
+                    ..StepPlan::full(total_steps as u16)
+                };
+                targeting.set_plan(plan)?;
+
+                (ScanMode::ScanUp, baseline, plan)
+            }
+            None => (
+                ScanMode::Baseline(Calibration::new(), Direction::Up),
+                [[0; MAX_STEPS]; 2],
+                // Calibration measures every step.
+                StepPlan::full(total_steps as u16),
+            ),
+        };
+
         Ok(Ranging {
             targeting,
             audio,
+            config,
+            adc_ratio,
             ticker,
             sensor,
             servo,
-            mode: ScanMode::Baseline(Calibration::new()),
+            mode,
             current_step: 0,
             total_steps,
-            baseline: [0; MAX_STEPS],
+            baseline,
+            last_distance: [0; MAX_STEPS],
+            still_sweeps: [0; MAX_STEPS],
+            recalibration_sweeps: Settings::default().recalibration_sweeps,
+            recalibration: None,
+            plan,
+            candidates: None,
+            run_state: RunState::Running,
         })
     }
 
@@ -100,31 +205,190 @@ impl Ranging {
 
         let distance = self.sensor.get_distance()?;
         self.sensor.clear_interrupt()?;
+        watchdog::heartbeat(Subsystem::Ranging);
 
-        if let ScanMode::Baseline(ref mut calibration) = self.mode {
+        if let ScanMode::Baseline(ref mut calibration, direction) = self.mode {
             if let CalibrationResult::Done(threshold) =
                 Self::process_calibration(calibration, distance)
             {
-                self.baseline[self.current_step] = threshold;
-                self.mode = ScanMode::Baseline(Calibration::new());
+                self.baseline[direction as usize][self.current_step] = threshold;
+                self.mode = ScanMode::Baseline(Calibration::new(), direction);
                 self.sensor.stop_ranging()?;
                 self.move_servo()?;
             } else {
                 // Get next scan in 200 ms
                 READ_SENSOR.call_at(self.ticker.now() + SENSOR_INTERMEASURMENT_TIME);
             }
+        } else if let Some(ref mut calibration) = self.recalibration {
+            if let CalibrationResult::Done(threshold) =
+                Self::process_calibration(calibration, distance)
+            {
+                let direction = self.mode.direction();
+                rprintln!(
+                    "step {} {:?} recalibrated, threshold {}",
+                    self.current_step,
+                    direction,
+                    threshold
+                );
+                self.baseline[direction as usize][self.current_step] = threshold;
+                self.recalibration = None;
+                // Keep the new scene over reboot, saved baseline would bring the old one back.
+                self.save_baseline()?;
+                self.next_step()?;
+            } else {
+                READ_SENSOR.call_at(self.ticker.now() + SENSOR_INTERMEASURMENT_TIME);
+            }
         } else {
             self.process_scan(distance)?;
-            self.sensor.stop_ranging()?;
 
-            if self.move_servo()? == MoveResult::ChangeDirection {
-                self.targeting.reset()?;
+            if self.is_scene_changed(distance)? {
+                // Keep ranging and collect new baseline for this step.
+                self.recalibration = Some(Calibration::new());
+                READ_SENSOR.call_at(self.ticker.now() + SENSOR_INTERMEASURMENT_TIME);
+            } else {
+                self.next_step()?;
             }
         }
 
         Ok(())
     }
 
+    fn save_baseline(&self) -> Result<(), Error> {
+        self.config
+            .save_baseline(self.adc_ratio, self.total_steps as u16, &self.baseline)
+    }
+
+    fn next_step(&mut self) -> Result<(), Error> {
+        self.sensor.stop_ranging()?;
+        self.move_servo()
+    }
+
+    // Threshold of the current step in the current direction.
+    fn threshold(&self) -> u16 {
+        self.baseline[self.mode.direction() as usize][self.current_step]
+    }
+
+    // Track still contact at the current step.
+    // Returns true when the step needs a new baseline.
+    fn is_scene_changed(&mut self, distance: u16) -> Result<bool, Error> {
+        let step = self.current_step;
+        let contact = distance < self.threshold();
+        let still = distance.abs_diff(self.last_distance[step]) <= STILL_DISTANCE_TOLERANCE;
+        self.last_distance[step] = distance;
+
+        // Locked target is a confirmed person, not a part of the scene.
+        if self.recalibration_sweeps == 0
+            || !(contact && still)
+            || self.targeting.is_locked_on(step as u16)?
+        {
+            self.still_sweeps[step] = 0;
+            return Ok(false);
+        }
+
+        self.still_sweeps[step] += 1;
+        if self.still_sweeps[step] < self.recalibration_sweeps {
+            return Ok(false);
+        }
+
+        rprintln!(
+            "step {} has still contact at {}, recalibrating",
+            step,
+            distance
+        );
+        self.still_sweeps[step] = 0;
+
+        Ok(true)
+    }
+
+    fn stop(&mut self) -> Result<(), Error> {
+        if self.run_state == RunState::Running {
+            self.halt()?;
+        }
+        self.run_state = RunState::Stopped;
+
+        Ok(())
+    }
+
+    fn pause(&mut self) -> Result<(), Error> {
+        if self.run_state == RunState::Running {
+            self.halt()?;
+            self.run_state = RunState::Paused;
+        }
+
+        Ok(())
+    }
+
+    fn resume(&mut self) {
+        if self.run_state == RunState::Paused {
+            // Servo keeps its position while disabled, give it time in case it was moved.
+            self.servo.enable();
+            START_RANGING.call_at(self.ticker.now() + SERVO_RESET_TIME);
+            watchdog::set_active(Subsystem::Ranging, true);
+            self.run_state = RunState::Running;
+        }
+    }
+
+    fn halt(&mut self) -> Result<(), Error> {
+        START_RANGING.cancel();
+        READ_SENSOR.cancel();
+        self.recalibration = None;
+
+        self.sensor.stop_ranging()?;
+        self.servo.disable();
+        watchdog::set_active(Subsystem::Ranging, false);
+
+        Ok(())
+    }
+
+    // Measure the baseline again from scratch, e.g. after the turret was moved.
+    fn recalibrate(&mut self) -> Result<(), Error> {
+        if self.run_state == RunState::Running {
+            START_RANGING.cancel();
+            READ_SENSOR.cancel();
+            self.sensor.stop_ranging()?;
+            START_RANGING.call_at(self.ticker.now() + SERVO_RESET_TIME);
+        }
+
+        self.mode = ScanMode::Baseline(Calibration::new(), Direction::Up);
+        self.recalibration = None;
+        self.candidates = None;
+        self.still_sweeps = [0; MAX_STEPS];
+        self.plan = StepPlan::full(self.total_steps as u16);
+        self.targeting.reset()?;
+        self.targeting.set_plan(self.plan)?;
+
+        self.current_step = 0;
+        self.servo.set(Ratio::zero())?;
+
+        Ok(())
+    }
+
+    fn dump_baseline(&self) {
+        let [up, down] = &self.baseline;
+        for step in 0..self.total_steps {
+            rprintln!(
+                "step {} threshold up {} down {}",
+                step,
+                up[step],
+                down[step]
+            );
+        }
+    }
+
+    // Check that sensor responds and calibration produced usable baseline.
+    fn self_test(&mut self) -> Result<bool, Error> {
+        let booted = self.sensor.boot_state()? == BootState::Booted;
+        let calibrated = !matches!(self.mode, ScanMode::Baseline(..))
+            && self
+                .baseline
+                .iter()
+                .all(|baseline| baseline[..self.total_steps].iter().all(|&t| t > 0));
+
+        rprintln!("sensor booted {}, calibrated {}", booted, calibrated);
+
+        Ok(booted && calibrated)
+    }
+
     fn process_calibration(calibration: &mut Calibration, distance: u16) -> CalibrationResult {
         rprintln!("cal {}", distance);
         calibration.add_sample(distance);
@@ -147,52 +411,100 @@ impl Ranging {
         }
     }
 
-    fn process_scan(&self, distance: u16) -> Result<(), Error> {
+    fn process_scan(&mut self, distance: u16) -> Result<(), Error> {
         rprintln!("run {}", distance);
 
-        self.targeting.report(
-            self.current_step as u16,
-            distance < self.baseline[self.current_step],
-        )
+        let step = self.current_step;
+        let contact = distance < self.threshold();
+
+        if contact && self.plan.stride > 1 {
+            self.candidates = Some(match self.candidates {
+                None => (step, step),
+                Some((low, high)) => (min(low, step), max(high, step)),
+            });
+        }
+
+        self.targeting.report(step as u16, distance, contact)
     }
 
-    fn move_servo(&mut self) -> Result<MoveResult, Error> {
-        let mut result = MoveResult::SameDirection;
+    fn move_servo(&mut self) -> Result<(), Error> {
+        let stride = self.plan.stride as usize;
 
-        #[allow(clippy::collapsible_else_if)]
-        if self.mode == ScanMode::ScanDown {
-            if self.current_step == 0 {
-                self.mode = ScanMode::ScanUp;
-                result = MoveResult::ChangeDirection;
-            } else {
-                self.current_step -= 1;
-            }
+        let next_step = if self.mode.direction() == Direction::Down {
+            self.current_step
+                .checked_sub(stride)
+                .filter(|&step| step >= self.plan.low as usize)
         } else {
-            if self.current_step == self.total_steps - 1 {
-                if let ScanMode::Baseline(_) = self.mode {
-                    // End of calibration, start looking for targets.
-                    self.audio.play(Sound::BeginScan);
-                }
-
-                self.mode = ScanMode::ScanDown;
-                result = MoveResult::ChangeDirection;
-            } else {
-                self.current_step += 1;
+            Some(self.current_step + stride).filter(|&step| step <= self.plan.high as usize)
+        };
+
+        match next_step {
+            Some(step) => {
+                self.current_step = step;
+                self.servo
+                    .set(Ratio::new(step as u16, self.total_steps as u16))?;
+
+                START_RANGING.call_at(self.ticker.now() + SERVO_STEP_TIME);
             }
+            None => self.end_sweep()?,
         }
 
-        if result == MoveResult::SameDirection {
-            self.servo.set(Ratio::new(
-                self.current_step as u16,
-                self.total_steps as u16,
-            ))?;
+        Ok(())
+    }
 
-            START_RANGING.call_at(self.ticker.now() + SERVO_STEP_TIME);
-        } else {
+    // Reverse direction and plan the next sweep: coarse over the full range,
+    // or fine around the contacts found by the coarse one.
+    fn end_sweep(&mut self) -> Result<(), Error> {
+        let last_step = self.total_steps - 1;
+
+        self.mode = match self.mode {
+            // Calibrate the other direction.
+            ScanMode::Baseline(_, Direction::Up) => {
+                ScanMode::Baseline(Calibration::new(), Direction::Down)
+            }
+            ScanMode::Baseline(_, Direction::Down) => {
+                // End of calibration, start looking for targets.
+                self.save_baseline()?;
+                self.audio.play(Sound::BeginScan);
+                ScanMode::ScanUp
+            }
+            ScanMode::ScanDown => ScanMode::ScanUp,
+            ScanMode::ScanUp => ScanMode::ScanDown,
+        };
+        let up = self.mode.direction() == Direction::Up;
+
+        let (low, high, stride) = match self.candidates.take() {
+            // Calibration measures every step.
+            _ if matches!(self.mode, ScanMode::Baseline(..)) => (0, last_step, 1),
+            Some((low, high)) if self.plan.stride > 1 => (
+                low.saturating_sub(FINE_WINDOW_MARGIN),
+                min(high + FINE_WINDOW_MARGIN, last_step),
+                1,
+            ),
+            _ => (0, last_step, COARSE_STRIDE),
+        };
+
+        self.plan = StepPlan {
+            low: low as u16,
+            high: high as u16,
+            stride: stride as u16,
+        };
+
+        self.targeting.reset()?;
+        self.targeting.set_plan(self.plan)?;
+
+        let first_step = if up { low } else { high };
+        if first_step == self.current_step {
             START_RANGING.call();
+        } else {
+            self.current_step = first_step;
+            self.servo
+                .set(Ratio::new(first_step as u16, self.total_steps as u16))?;
+
+            START_RANGING.call_at(self.ticker.now() + SERVO_JUMP_TIME);
         }
 
-        Ok(result)
+        Ok(())
     }
 }
 
@@ -211,11 +523,14 @@ impl StaticState {
         *self.state.borrow_mut() = Some(state);
     }
 
-    fn with(&self, f: fn(&mut Ranging) -> Result<(), Error>) {
+    fn with<F, R>(&self, f: F) -> Result<R, Error>
+    where
+        F: Fn(&mut Ranging) -> Result<R, Error>,
+    {
         let mut stref = self.state.borrow_mut();
-        let state = stref.as_mut().ok_or(Error::Uninitialized).unwrap();
+        let state = stref.as_mut().ok_or(Error::Uninitialized)?;
 
-        f(state).unwrap();
+        f(state)
     }
 }
 
@@ -225,8 +540,9 @@ unsafe impl Sync for StaticState {}
 
 static STATE: StaticState = StaticState::new();
 
-static START_RANGING: Event = Event::new(&|| STATE.with(|state| state.start_measurement()));
-static READ_SENSOR: Event = Event::new(&|| STATE.with(|state| state.read_sensor()));
+static START_RANGING: Event =
+    Event::new_fallible(&|| STATE.with(|state| state.start_measurement()));
+static READ_SENSOR: Event = Event::new_fallible(&|| STATE.with(|state| state.read_sensor()));
 
 pub fn get_num_steps_from_angle_scale(scale: Ratio<u16>) -> Result<usize, Error> {
     if scale > Ratio::one() {
@@ -240,21 +556,74 @@ pub fn get_num_steps_from_angle_scale(scale: Ratio<u16>) -> Result<usize, Error>
     Ok(total_steps)
 }
 
+// Stop scanning and disable the servo.
+// NOT interrupt-safe
+pub fn stop() -> Result<(), Error> {
+    STATE.with(|state| state.stop())
+}
+
+// Stop scanning until resume() is called.
+// NOT interrupt-safe
+pub fn pause() -> Result<(), Error> {
+    STATE.with(|state| state.pause())
+}
+
+// Continue paused scanning, does nothing after stop().
+// NOT interrupt-safe
+pub fn resume() -> Result<(), Error> {
+    STATE.with(|state| {
+        state.resume();
+        Ok(())
+    })
+}
+
+// Discard the baseline and calibrate again.
+// NOT interrupt-safe
+pub fn recalibrate() -> Result<(), Error> {
+    STATE.with(|state| state.recalibrate())
+}
+
+// NOT interrupt-safe
+pub fn apply_settings(settings: &Settings) -> Result<(), Error> {
+    STATE.with(|state| {
+        state.recalibration_sweeps = settings.recalibration_sweeps;
+        Ok(())
+    })
+}
+
+// Print baseline thresholds for all steps.
+// NOT interrupt-safe
+pub fn dump_baseline() -> Result<(), Error> {
+    STATE.with(|state| {
+        state.dump_baseline();
+        Ok(())
+    })
+}
+
+// NOT interrupt-safe
+pub fn self_test() -> Result<bool, Error> {
+    STATE.with(|state| state.self_test())
+}
+
+#[allow(clippy::too_many_arguments)]
 pub fn start(
     ticker: Ticker,
     event_queue: &mut EventQueue<'_, 'static>,
     sensor: Sensor,
     servo: SensorServo,
     num_steps: usize,
+    adc_ratio: Ratio<u16>,
     targeting: Targeting,
     audio: Audio,
+    config: Config,
 ) -> Result<(), Error> {
     event_queue.bind(&START_RANGING);
     event_queue.bind(&READ_SENSOR);
 
     STATE.set(Ranging::init(
-        ticker, sensor, servo, num_steps, targeting, audio,
+        ticker, sensor, servo, num_steps, adc_ratio, targeting, audio, config,
     )?);
+    watchdog::set_active(Subsystem::Ranging, true);
 
     Ok(())
 }
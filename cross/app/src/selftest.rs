@@ -0,0 +1,190 @@
+// Hardware self-test, run at boot while the button is held or after a reboot
+// requested with a button double press.
+// Checks run one by one and are reported over RTT. The first failed check
+// is blinked on the target lock LED forever: its number of blinks, then a pause.
+// LED, laser and audio checks can't detect failures, they are for the eye and ear.
+
+use crate::board::Board;
+use crate::error::Error;
+use crate::system_time::Duration;
+
+use core::mem::MaybeUninit;
+use core::ptr::addr_of_mut;
+use cortex_m::asm::delay;
+use cortex_m::peripheral::SCB;
+use num::rational::Ratio;
+use num::{One, Zero};
+use rtt_target::rprintln;
+use simplefs::FileSystem;
+use stm32f1xx_hal::timer::Channel;
+
+const SERVO_MOVE_TIME: Duration = Duration::millis(1000);
+const BLINK_TIME: Duration = Duration::millis(200);
+const CODE_PAUSE_TIME: Duration = Duration::millis(1500);
+const LASER_PULSE_TIME: Duration = Duration::millis(100);
+
+// VL53L1X model ID register value.
+const SENSOR_MODEL_ID: u16 = 0xeacc;
+
+// Test tone is a square wave driven by busy waiting, 1 kHz for half a second.
+const TONE_HALF_PERIOD_CYCLES: u32 = 32_000;
+const TONE_PERIODS: u32 = 500;
+const TONE_DUTY: u16 = 192;
+
+// Reboot request survives the reset in RAM that isn't initialized at startup.
+const REQUEST_MAGIC: u32 = 0x5e1f_7e57;
+
+#[link_section = ".uninit.SELFTEST_REQUEST"]
+static mut REQUEST: MaybeUninit<u32> = MaybeUninit::uninit();
+
+#[derive(Clone, Copy, Debug)]
+enum Check {
+    Led = 1,
+    Servos,
+    Laser,
+    Sensor,
+    FileSystem,
+    Audio,
+}
+
+// Watchdog is already running, self-test feeds it while waiting.
+fn wait(board: &mut Board, duration: Duration) {
+    let deadline = board.ticker.now() + duration;
+    while board.ticker.now() < deadline {
+        board.watchdog.feed();
+        board.ticker.wait_for_tick();
+    }
+}
+
+fn blink(board: &mut Board, times: u32) {
+    for _ in 0..times {
+        board.target_lock_led.set_high();
+        wait(board, BLINK_TIME);
+        board.target_lock_led.set_low();
+        wait(board, BLINK_TIME);
+    }
+}
+
+fn sweep_servos(board: &mut Board) -> Result<(), Error> {
+    for position in [Ratio::zero(), Ratio::one(), Ratio::zero()] {
+        board.sensor_servo.set(position)?;
+        board.laser_servo.set(position)?;
+        wait(board, SERVO_MOVE_TIME);
+    }
+
+    Ok(())
+}
+
+fn pulse_laser(board: &mut Board) {
+    board.laser_led.set_high();
+    wait(board, LASER_PULSE_TIME);
+    board.laser_led.set_low();
+}
+
+fn check_sensor(board: &mut Board) -> Result<bool, Error> {
+    let model_id = board.sensor.get_sensor_id()?;
+    rprintln!("sensor model id {:x}", model_id);
+
+    Ok(model_id == SENSOR_MODEL_ID)
+}
+
+fn mount_filesystem(board: &mut Board) -> Result<(), Error> {
+    FileSystem::mount(board.storage)?;
+
+    Ok(())
+}
+
+fn play_tone(board: &mut Board) {
+    board.audio_enable.set_high();
+    board.audio_pwm.enable(Channel::C3);
+
+    for _ in 0..TONE_PERIODS {
+        board.audio_pwm.set_duty(Channel::C3, TONE_DUTY);
+        delay(TONE_HALF_PERIOD_CYCLES);
+        board.audio_pwm.set_duty(Channel::C3, 0);
+        delay(TONE_HALF_PERIOD_CYCLES);
+    }
+
+    board.audio_pwm.disable(Channel::C3);
+    board.audio_enable.set_low();
+}
+
+fn run_check(board: &mut Board, check: Check) -> Result<bool, Error> {
+    match check {
+        Check::Led => blink(board, 3),
+        Check::Servos => sweep_servos(board)?,
+        Check::Laser => pulse_laser(board),
+        Check::Sensor => return check_sensor(board),
+        Check::FileSystem => mount_filesystem(board)?,
+        Check::Audio => play_tone(board),
+    }
+
+    Ok(true)
+}
+
+// Takes the reboot request, so the self-test runs once per request.
+pub fn is_requested(board: &Board) -> bool {
+    // Called once at boot before interrupts are enabled, so it is safe to access the static.
+    let requested = unsafe {
+        let request = addr_of_mut!(REQUEST).cast::<u32>();
+        let requested = request.read_volatile() == REQUEST_MAGIC;
+        request.write_volatile(0);
+        requested
+    };
+
+    requested || board.button.is_high()
+}
+
+// Reboot and run the self-test.
+pub fn request() -> ! {
+    // Nothing else accesses the request while the system is running.
+    unsafe {
+        addr_of_mut!(REQUEST)
+            .cast::<u32>()
+            .write_volatile(REQUEST_MAGIC)
+    };
+
+    SCB::sys_reset()
+}
+
+// Run all checks. Returns if all of them passed, otherwise blinks the failure code forever.
+pub fn run(board: &mut Board) {
+    rprintln!("self-test started");
+
+    let mut failed = None;
+    for check in [
+        Check::Led,
+        Check::Servos,
+        Check::Laser,
+        Check::Sensor,
+        Check::FileSystem,
+        Check::Audio,
+    ] {
+        let passed = match run_check(board, check) {
+            Ok(passed) => passed,
+            Err(err) => {
+                rprintln!("self-test {:?} error: {:?}", check, err);
+                false
+            }
+        };
+
+        rprintln!(
+            "self-test {:?}: {}",
+            check,
+            if passed { "ok" } else { "FAILED" }
+        );
+        if !passed && failed.is_none() {
+            failed = Some(check);
+        }
+    }
+
+    let Some(check) = failed else {
+        rprintln!("self-test passed");
+        return;
+    };
+
+    loop {
+        blink(board, check as u32);
+        wait(board, CODE_PAUSE_TIME);
+    }
+}
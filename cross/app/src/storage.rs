@@ -1,21 +1,75 @@
 use crate::board::{SpiBus, SpiCs};
 
 use core::cell::RefCell;
-use spi_memory::Read;
+use spi_memory::{BlockDevice, Read};
 
 type SpiMemory = spi_memory::series25::Flash<SpiBus, SpiCs>;
 pub type StorageError = spi_memory::Error<SpiBus, SpiCs>;
 
-pub struct SoundStorage {
-    flash: RefCell<SpiMemory>,
+const FLASH_SIZE: usize = 2 * 1024 * 1024;
+pub const SECTOR_LEN: usize = 4096;
+
+// The last sector holds flash-writer progress log, settings are in the one before it,
+// ranging baseline in the one before settings.
+// Filesystem image must end before them.
+pub const CONFIG_ADDRESS: usize = FLASH_SIZE - 2 * SECTOR_LEN;
+pub const BASELINE_ADDRESS: usize = FLASH_SIZE - 3 * SECTOR_LEN;
+
+struct StaticFlash {
+    flash: RefCell<Option<SpiMemory>>,
 }
 
-impl SoundStorage {
-    const FLASH_SIZE: usize = 2 * 1024 * 1024;
+impl StaticFlash {
+    const fn new() -> Self {
+        Self {
+            flash: RefCell::new(None),
+        }
+    }
+
+    fn set(&self, flash: SpiMemory) {
+        *self.flash.borrow_mut() = Some(flash);
+    }
+
+    fn with<F, R>(&self, f: F) -> Result<R, StorageError>
+    where
+        F: FnOnce(&mut SpiMemory) -> Result<R, StorageError>,
+    {
+        let mut flashref = self.flash.borrow_mut();
+        // SoundStorage is only created after the flash is set.
+        let flash = flashref.as_mut().unwrap();
+
+        f(flash)
+    }
+}
 
+// FLASH is only accessed from the main thread.
+// Therefore, no locking is necessary.
+unsafe impl Sync for StaticFlash {}
+
+static FLASH: StaticFlash = StaticFlash::new();
+
+// Handle to the flash chip shared by the filesystem and settings.
+#[derive(Clone, Copy)]
+pub struct SoundStorage;
+
+impl SoundStorage {
     pub fn new(spi: SpiBus, cs: SpiCs) -> Result<Self, simplefs::Error<StorageError>> {
-        Ok(Self {
-            flash: RefCell::new(SpiMemory::init(spi, cs)?),
+        FLASH.set(SpiMemory::init(spi, cs)?);
+
+        Ok(SoundStorage)
+    }
+
+    pub fn read_bytes(&self, address: usize, buf: &mut [u8]) -> Result<(), StorageError> {
+        FLASH.with(|flash| flash.read(address as u32, buf))
+    }
+
+    // Erase the sector at `address` and write data to its start.
+    pub fn write_sector(&self, address: usize, data: &mut [u8]) -> Result<(), StorageError> {
+        debug_assert!(address % SECTOR_LEN == 0 && data.len() <= SECTOR_LEN);
+
+        FLASH.with(|flash| {
+            flash.erase_sectors(address as u32, 1)?;
+            flash.write_bytes(address as u32, data)
         })
     }
 }
@@ -24,10 +78,10 @@ impl simplefs::Storage for SoundStorage {
     type Error = StorageError;
 
     fn capacity(&self) -> usize {
-        Self::FLASH_SIZE
+        BASELINE_ADDRESS
     }
 
     fn read(&self, off: usize, buf: &mut [u8]) -> Result<(), Self::Error> {
-        self.flash.borrow_mut().read(off as u32, buf)
+        self.read_bytes(off, buf)
     }
 }
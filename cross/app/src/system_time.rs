@@ -1,31 +1,62 @@
 #![deny(unsafe_code)]
 
-use core::cell::Cell;
+use core::cell::{Cell, RefCell};
+use cortex_m::asm::wfi;
+use cortex_m::peripheral::syst::SystClkSource;
+use cortex_m::peripheral::{SCB, SYST};
 use cortex_m_rt::exception;
 use critical_section::Mutex;
-use fugit::RateExtU32;
-use stm32f1xx_hal::pac::SYST;
-use stm32f1xx_hal::timer::{SysEvent, Timer};
+use stm32f1xx_hal::rcc::Clocks;
 
 const HERTZ: u32 = 100;
+// SysTick counter is 24 bits wide.
+const MAX_RELOAD: u32 = 0x00FF_FFFF;
 
 pub type Instant = fugit::TimerInstantU32<HERTZ>;
 pub type Duration = fugit::TimerDurationU32<HERTZ>;
 
+// 32-bit ticks wrap after 497 days, and comparisons break for timestamps
+// more than half of that apart. Use 64-bit time for timestamps that can get old.
+// Durations convert with Duration64::from(), event deadlines stay 32-bit.
+pub type Instant64 = fugit::TimerInstantU64<HERTZ>;
+pub type Duration64 = fugit::TimerDurationU64<HERTZ>;
+
 static TICKS: Mutex<Cell<u32>> = Mutex::new(Cell::new(0));
+// Number of times TICKS wrapped around, high half of the 64-bit tick count.
+static EPOCHS: Mutex<Cell<u32>> = Mutex::new(Cell::new(0));
+static SYSTICK: Mutex<RefCell<Option<SYST>>> = Mutex::new(RefCell::new(None));
+
+fn add_ticks(cs: critical_section::CriticalSection, elapsed: u32) {
+    let ticks = TICKS.borrow(cs);
+    let (new_ticks, wrapped) = ticks.get().overflowing_add(elapsed);
+    ticks.set(new_ticks);
+
+    if wrapped {
+        let epochs = EPOCHS.borrow(cs);
+        epochs.set(epochs.get().wrapping_add(1));
+    }
+}
 
 #[derive(Clone, Copy, Debug)]
-pub struct Ticker {}
+pub struct Ticker {
+    // SysTick cycles per tick.
+    reload: u32,
+}
 
 impl Ticker {
     // Setup SysTick to tick at 100Hz
-    pub fn new(syst: Timer<SYST>) -> Self {
-        let mut counter = syst.counter_hz();
+    pub fn new(mut syst: SYST, clocks: &Clocks) -> Self {
+        let reload = clocks.hclk().raw() / HERTZ;
 
-        counter.start(HERTZ.Hz()).unwrap();
-        counter.listen(SysEvent::Update);
+        syst.set_clock_source(SystClkSource::Core);
+        syst.set_reload(reload - 1);
+        syst.clear_current();
+        syst.enable_interrupt();
+        syst.enable_counter();
 
-        Ticker {}
+        critical_section::with(|cs| SYSTICK.borrow_ref_mut(cs).replace(syst));
+
+        Ticker { reload }
     }
 
     // Get current tick count
@@ -33,23 +64,102 @@ impl Ticker {
         critical_section::with(|cs| TICKS.borrow(cs).get())
     }
 
+    // Get 64-bit tick count, never wraps in practice.
+    pub fn get_ticks64(&self) -> u64 {
+        critical_section::with(|cs| {
+            let epochs = EPOCHS.borrow(cs).get();
+            let ticks = TICKS.borrow(cs).get();
+            (u64::from(epochs) << 32) | u64::from(ticks)
+        })
+    }
+
     // Get timestamp
     pub fn now(&self) -> Instant {
         let ticks = self.get_ticks();
         Instant::from_ticks(ticks)
     }
 
+    // Get 64-bit timestamp
+    pub fn now64(&self) -> Instant64 {
+        Instant64::from_ticks(self.get_ticks64())
+    }
+
     // Wait for the next tick.
     // Makes sure the ticker is enabled.
     pub fn wait_for_tick(&self) {
-        cortex_m::asm::wfi();
+        wfi();
+    }
+
+    // Sleep until the next deadline or an interrupt, skipping SysTick interrupts in between.
+    // `next_deadline` is called with interrupts disabled, so an event posted
+    // by interrupt handler right before sleep can't be missed.
+    // Falls back to waking up every tick if the deadline is too close.
+    pub fn sleep<F>(&self, next_deadline: F)
+    where
+        F: FnOnce(u32) -> Option<u32>,
+    {
+        critical_section::with(|cs| {
+            let ticks = TICKS.borrow(cs);
+            let max_idle_ticks = MAX_RELOAD / self.reload;
+            let idle_ticks = match next_deadline(ticks.get()) {
+                None => max_idle_ticks,
+                Some(deadline) => (deadline.wrapping_sub(ticks.get()) as i32)
+                    .clamp(0, max_idle_ticks as i32) as u32,
+            };
+
+            if idle_ticks <= 1 || SCB::is_pendst_pending() {
+                // Wake up on the next tick as usual.
+                wfi();
+                return;
+            }
+
+            let mut systick = SYSTICK.borrow_ref_mut(cs);
+            let syst = systick.as_mut().unwrap();
+
+            // Cycles left until the current tick ends.
+            syst.disable_counter();
+            let tick_left = SYST::get_current();
+            let sleep_cycles = tick_left + (idle_ticks - 1) * self.reload;
+
+            syst.set_reload(sleep_cycles - 1);
+            syst.clear_current();
+            syst.enable_counter();
+
+            wfi();
+
+            syst.disable_counter();
+            let elapsed_ticks = if syst.has_wrapped() {
+                // Slept until the deadline. Skipped ticks are counted here,
+                // don't let SysTick handler count the last one again.
+                SCB::clear_pendst();
+
+                syst.set_reload(self.reload - 1);
+                syst.clear_current();
+                syst.enable_counter();
+
+                idle_ticks
+            } else {
+                // Woken up early by another interrupt.
+                let slept = sleep_cycles - 1 - SYST::get_current();
+                let since_last_tick = self.reload - tick_left + slept;
+
+                // Finish the current tick, then go back to normal ticking.
+                // Counter loads the reload value on the next cycle after clear.
+                let remainder = self.reload - since_last_tick % self.reload;
+                syst.set_reload(remainder - 1);
+                syst.clear_current();
+                syst.enable_counter();
+                syst.set_reload(self.reload - 1);
+
+                since_last_tick / self.reload
+            };
+
+            add_ticks(cs, elapsed_ticks);
+        });
     }
 }
 
 #[exception]
 fn SysTick() {
-    critical_section::with(|cs| {
-        let ticks = TICKS.borrow(cs).get();
-        TICKS.borrow(cs).set(ticks + 1);
-    });
+    critical_section::with(|cs| add_ticks(cs, 1));
 }
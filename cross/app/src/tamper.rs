@@ -0,0 +1,179 @@
+use crate::accelerometer::Acceleration;
+use crate::audio::{Audio, Sound};
+use crate::board::Accelerometer;
+use crate::error::Error;
+use crate::event_queue::{Event, EventQueue, ExtEvent};
+use crate::system_time::{Duration, Ticker};
+use crate::targeting::{PauseReason, Targeting};
+
+use core::cell::RefCell;
+use rtt_target::rprintln;
+
+const SAMPLE_INTERVAL: Duration = Duration::millis(100);
+
+// Deviation from the resting position to consider the turret moved, in milli-g.
+const MOTION_THRESHOLD: u16 = 250;
+// Max difference between consecutive samples while standing still, in milli-g.
+const STILL_THRESHOLD: u16 = 50;
+// Number of consecutive samples to detect the turret being picked up.
+const PICKED_UP_SAMPLES: u8 = 3;
+// Number of consecutive samples to detect the turret being put down.
+const PUT_DOWN_SAMPLES: u8 = 20;
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+enum MotionState {
+    Stationary {
+        rest: Acceleration,
+        moving_samples: u8,
+    },
+    PickedUp {
+        last: Acceleration,
+        still_samples: u8,
+    },
+}
+
+struct State {
+    ticker: Ticker,
+    accelerometer: Accelerometer,
+    targeting: Targeting,
+    audio: Audio,
+    motion: MotionState,
+}
+
+impl State {
+    fn init(
+        ticker: Ticker,
+        mut accelerometer: Accelerometer,
+        targeting: Targeting,
+        audio: Audio,
+    ) -> Result<Self, Error> {
+        let rest = accelerometer.read()?;
+        rprintln!("resting position {:?}", rest);
+
+        SAMPLE_MOTION.call_at(ticker.now() + SAMPLE_INTERVAL);
+
+        Ok(State {
+            ticker,
+            accelerometer,
+            targeting,
+            audio,
+            motion: MotionState::Stationary {
+                rest,
+                moving_samples: 0,
+            },
+        })
+    }
+
+    fn sample(&mut self) -> Result<(), Error> {
+        let sample = self.accelerometer.read()?;
+
+        self.motion = match self.motion {
+            MotionState::Stationary {
+                rest,
+                moving_samples,
+            } => {
+                let moving_samples = if rest.distance(&sample) > MOTION_THRESHOLD {
+                    moving_samples + 1
+                } else {
+                    0
+                };
+
+                if moving_samples == PICKED_UP_SAMPLES {
+                    rprintln!("picked up {:?}", sample);
+                    self.targeting.set_picked_up(true)?;
+                    self.targeting.pause(PauseReason::PickedUp)?;
+                    self.audio.play(Sound::PickedUp);
+
+                    MotionState::PickedUp {
+                        last: sample,
+                        still_samples: 0,
+                    }
+                } else {
+                    MotionState::Stationary {
+                        rest,
+                        moving_samples,
+                    }
+                }
+            }
+            MotionState::PickedUp {
+                last,
+                still_samples,
+            } => {
+                let still_samples = if last.distance(&sample) <= STILL_THRESHOLD {
+                    still_samples + 1
+                } else {
+                    0
+                };
+
+                if still_samples == PUT_DOWN_SAMPLES {
+                    // Turret may be put down in a different position.
+                    rprintln!("put down {:?}", sample);
+                    self.targeting.set_picked_up(false)?;
+                    self.targeting.resume(PauseReason::PickedUp)?;
+
+                    MotionState::Stationary {
+                        rest: sample,
+                        moving_samples: 0,
+                    }
+                } else {
+                    MotionState::PickedUp {
+                        last: sample,
+                        still_samples,
+                    }
+                }
+            }
+        };
+
+        SAMPLE_MOTION.call_at(self.ticker.now() + SAMPLE_INTERVAL);
+
+        Ok(())
+    }
+}
+
+struct StaticState {
+    state: RefCell<Option<State>>,
+}
+
+impl StaticState {
+    const fn new() -> Self {
+        Self {
+            state: RefCell::new(None),
+        }
+    }
+
+    fn set(&self, state: State) {
+        *self.state.borrow_mut() = Some(state);
+    }
+
+    fn with<F, R>(&self, f: F) -> Result<R, Error>
+    where
+        F: Fn(&mut State) -> Result<R, Error>,
+    {
+        let mut stref = self.state.borrow_mut();
+        let state = stref.as_mut().ok_or(Error::Uninitialized)?;
+
+        f(state)
+    }
+}
+
+// STATE is only accessed from the main thread via EventQueue.
+// Therefore, no locking is necessary.
+unsafe impl Sync for StaticState {}
+
+static STATE: StaticState = StaticState::new();
+
+static SAMPLE_MOTION: Event = Event::new_fallible(&|| STATE.with(|state| state.sample()));
+
+pub fn start(
+    ticker: Ticker,
+    event_queue: &mut EventQueue<'_, 'static>,
+    accelerometer: Accelerometer,
+    targeting: Targeting,
+    audio: Audio,
+) -> Result<(), Error> {
+    event_queue.bind(&SAMPLE_MOTION);
+
+    STATE.set(State::init(ticker, accelerometer, targeting, audio)?);
+
+    Ok(())
+}
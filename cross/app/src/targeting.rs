@@ -1,42 +1,193 @@
 use crate::audio::{Audio, Sound};
 use crate::board::{Laser, LaserServo, Led};
+use crate::config::{SelectionPolicy, Settings};
 use crate::error::Error;
 use crate::event_queue::{Event, EventQueue, ExtEvent};
-use crate::system_time::{Duration, Instant, Ticker};
+use crate::ranging::StepPlan;
+use crate::system_time::{Duration, Duration64, Instant, Instant64, Ticker};
+use crate::watchdog::{self, Subsystem};
 
 use core::cell::RefCell;
 use core::cmp::{max, min};
+use fastrand::Rng;
+use heapless::{Deque, Vec};
 use num::rational::Ratio;
 use num::Zero;
+use rtt_target::rprintln;
+use safety::{Denied, SafetyPolicy};
+
+// Max number of separate targets tracked in one sweep.
+const MAX_TARGETS: usize = 8;
+// Number of sweeps another target must be preferred before the laser switches to it.
+const TARGET_SWITCH_SWEEPS: u8 = 3;
+
+// Number of sweeps the locked target position is remembered for lead estimation.
+const TRACK_LEN: usize = 5;
+const TRACK_MIN_SAMPLES: usize = 3;
+// Max deviation of tracked positions from a straight line, steps.
+// Noisier tracks are aimed at the middle.
+const MAX_TRACK_ERROR: i64 = 2;
+
+// Laser safety limits.
+const MAX_LASER_ON_TIME: Duration = Duration::secs(30);
+const LASER_COOLDOWN: Duration = Duration::secs(10);
+const SAFETY_CHECK_INTERVAL: Duration = Duration::millis(100);
 
-const MIN_TARGET_LOCK_RANGE: u16 = 8;
-const MAX_TARGET_BREAK_RANGE: u16 = 4;
-
-const LASER_OFF_DELAY: Duration = Duration::secs(5);
-const TARGET_LOST_DELAY: Duration = Duration::secs(60);
 const TARGET_ACQUIRED_INTERVAL: Duration = Duration::secs(30);
 
-#[derive(Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord)]
-enum TargetState {
-    NoContact,
-    EarlyContact {
-        start_position: u16,
-    },
-    Lock {
-        start_position: u16,
-        end_position: u16,
-    },
+// Patrol moves the laser servo one step at a time and waits at the ends of the arc.
+const PATROL_STEP_TIME: Duration = Duration::millis(100);
+const PATROL_DWELL_MS: core::ops::RangeInclusive<u32> = 1000..=5000;
+
+// Each reason is cleared separately, targeting runs when none is left.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum PauseReason {
+    PickedUp = 1,
+    Standby = 2,
+    // Critical battery, can't be resumed.
+    Shutdown = 4,
+}
+
+// Range of steps with contact.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+struct Span {
+    low: u16,
+    high: u16,
+    // Closest distance seen in the span.
+    distance: u16,
+    // Number of consecutive sweeps the target was seen in.
+    sweeps: u16,
+    // Time the center of the span was scanned.
+    seen: Instant,
+}
+
+impl Span {
+    fn new(position: u16, distance: u16, seen: Instant) -> Self {
+        Span {
+            low: position,
+            high: position,
+            distance,
+            sweeps: 1,
+            seen,
+        }
+    }
+
+    fn add(&mut self, position: u16, distance: u16) {
+        self.low = min(self.low, position);
+        self.high = max(self.high, position);
+        self.distance = min(self.distance, distance);
+    }
+
+    fn width(&self) -> u16 {
+        self.high - self.low
+    }
+
+    fn center(&self) -> u16 {
+        self.low + self.width() / 2
+    }
+
+    fn overlaps(&self, other: &Span) -> bool {
+        self.low <= other.high && other.low <= self.high
+    }
+
+    fn is_better(&self, other: &Span, policy: SelectionPolicy) -> bool {
+        match policy {
+            SelectionPolicy::Nearest => self.distance < other.distance,
+            SelectionPolicy::Widest => self.width() > other.width(),
+            SelectionPolicy::MostPersistent => self.sweeps > other.sweeps,
+        }
+    }
+}
+
+// Contact currently being scanned.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+struct Contact {
+    span: Span,
+    last_position: u16,
+    last_seen: Instant,
+}
+
+// Laser servo sweep while there is no target.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+struct Patrol {
+    position: u16,
+    low: u16,
+    high: u16,
+    forward: bool,
+}
+
+// Estimate how far the target moves in `lead_ms` from its recent positions.
+// Fits a straight line, None if there is too little data or it doesn't fit.
+fn lead_offset(track: &Deque<(Instant, u16), TRACK_LEN>, lead_ms: u32) -> Option<i32> {
+    if track.len() < TRACK_MIN_SAMPLES {
+        return None;
+    }
+
+    let (start, _) = *track.front()?;
+    let samples = track
+        .iter()
+        .map(|&(seen, position)| ((seen - start).to_millis() as i64, position as i64));
+
+    let n = track.len() as i64;
+    let (mut st, mut sx, mut stt, mut stx) = (0, 0, 0, 0);
+    for (t, x) in samples.clone() {
+        st += t;
+        sx += x;
+        stt += t * t;
+        stx += t * x;
+    }
+
+    // Velocity is slope / denom steps per ms.
+    let denom = n * stt - st * st;
+    if denom == 0 {
+        return None;
+    }
+    let slope = n * stx - st * sx;
+
+    // Distance from the fitted line, scaled by n * denom to stay in integers.
+    let noisy = samples.map(|(t, x)| (x * n - sx) * denom - slope * (t * n - st));
+    if noisy.map(i64::abs).max()? > MAX_TRACK_ERROR * n * denom {
+        return None;
+    }
+
+    Some((slope * lead_ms as i64 / denom) as i32)
 }
 
 struct State {
-    target_state: TargetState,
-    last_lock: Instant,
+    contact: Option<Contact>,
+    // Targets found in the current sweep.
+    targets: Vec<Span, MAX_TARGETS>,
+    // Targets found in the previous sweep.
+    previous_targets: Vec<Span, MAX_TARGETS>,
+    // Target the laser points at.
+    lock: Option<Span>,
+    // Number of sweeps another target was preferred over the locked one.
+    challenger_sweeps: u8,
+    // Positions of the locked target in the recent sweeps.
+    track: Deque<(Instant, u16), TRACK_LEN>,
+    last_lock: Instant64,
     ticker: Ticker,
     led: Led,
     laser: Laser,
     servo: LaserServo,
+    safety: SafetyPolicy,
+    // Last reason the laser was refused, logged once instead of on every step.
+    denied: Option<Denied>,
     total_steps: u16,
+    // Distance between reported positions in the current sweep.
+    stride: u16,
     audio: Audio,
+    random: Rng,
+    patrol: Option<Patrol>,
+    // Bit mask of PauseReason.
+    pause_reasons: u8,
+    min_target_lock_range: u16,
+    max_target_break_range: u16,
+    laser_off_delay: Duration,
+    target_lost_delay: Duration,
+    lead_time: Duration,
+    patrol_arc: u16,
+    selection_policy: SelectionPolicy,
 }
 
 impl State {
@@ -47,80 +198,391 @@ impl State {
         mut servo: LaserServo,
         total_steps: u16,
         audio: Audio,
+        random: Rng,
     ) -> Result<Self, Error> {
         servo.set(Ratio::zero())?;
 
-        Ok(State {
-            target_state: TargetState::NoContact,
-            last_lock: Instant::from_ticks(0),
+        let settings = Settings::default();
+
+        let mut state = State {
+            contact: None,
+            targets: Vec::new(),
+            previous_targets: Vec::new(),
+            lock: None,
+            challenger_sweeps: 0,
+            track: Deque::new(),
+            last_lock: Instant64::from_ticks(0),
             ticker,
             led,
             laser,
             servo,
+            safety: SafetyPolicy::new(MAX_LASER_ON_TIME.ticks(), LASER_COOLDOWN.ticks()),
+            denied: None,
             total_steps,
+            stride: 1,
             audio,
-        })
+            random,
+            patrol: None,
+            pause_reasons: 0,
+            min_target_lock_range: 0,
+            max_target_break_range: 0,
+            laser_off_delay: Duration::from_ticks(0),
+            target_lost_delay: Duration::from_ticks(0),
+            lead_time: Duration::from_ticks(0),
+            patrol_arc: 0,
+            selection_policy: SelectionPolicy::Nearest,
+        };
+        state.apply_settings(&settings);
+
+        Ok(state)
     }
 
-    fn reset(&mut self) {
-        self.target_state = TargetState::NoContact;
+    fn apply_settings(&mut self, settings: &Settings) {
+        self.min_target_lock_range = settings.min_target_lock_range;
+        self.max_target_break_range = settings.max_target_break_range;
+        self.laser_off_delay = Duration::secs(settings.laser_off_delay.into());
+        self.target_lost_delay = Duration::secs(settings.target_lost_delay.into());
+        self.lead_time = Duration::millis(settings.lead_time.into());
+        self.patrol_arc = min(settings.patrol_arc, 100);
+        self.selection_policy = settings.selection_policy;
+        if self.patrol_arc == 0 {
+            self.stop_patrol();
+        }
     }
 
-    fn laser_off(&mut self) {
+    // End of sweep, pick the target for the next one.
+    fn reset(&mut self) -> Result<(), Error> {
+        self.close_contact();
+        self.select_target()?;
+
+        self.previous_targets = self.targets.clone();
+        self.targets.clear();
+
+        Ok(())
+    }
+
+    // Contacts are measured with less detail in coarse sweeps.
+    fn set_plan(&mut self, plan: StepPlan) {
+        self.stride = plan.stride;
+    }
+
+    // Steps between measurements may have contact too.
+    fn is_wide_enough(&self, span: &Span) -> bool {
+        span.width() + (self.stride - 1) >= self.min_target_lock_range
+    }
+
+    fn is_paused(&self) -> bool {
+        self.pause_reasons != 0
+    }
+
+    fn pause(&mut self, reason: PauseReason) {
+        self.pause_reasons |= reason as u8;
+        self.contact = None;
+        self.targets.clear();
+        self.lock = None;
+        self.challenger_sweeps = 0;
+        self.track.clear();
+
+        self.led.set_low();
+        self.disable_laser();
+        self.stop_patrol();
+        LASER_OFF.cancel();
+        TARGET_LOST.cancel();
+    }
+
+    // Clear one pause reason, targeting stays paused if there are others.
+    fn resume(&mut self, reason: PauseReason) {
+        if reason != PauseReason::Shutdown {
+            self.pause_reasons &= !(reason as u8);
+        }
+    }
+
+    // Turn everything off, can't be resumed.
+    fn shutdown(&mut self) {
+        self.pause(PauseReason::Shutdown);
+        self.servo.disable();
+    }
+
+    fn set_picked_up(&mut self, picked_up: bool) {
+        self.safety.set_picked_up(picked_up);
+        self.check_safety();
+    }
+
+    // Position is covered by the locked target.
+    fn is_locked_on(&self, position: u16) -> bool {
+        self.lock
+            .is_some_and(|lock| lock.low <= position && position <= lock.high)
+    }
+
+    fn set_button_held(&mut self, held: bool) {
+        self.safety.set_button_held(held);
+        self.check_safety();
+    }
+
+    // Turn the laser on if the safety policy allows it.
+    fn enable_laser(&mut self) {
+        let was_on = self.safety.is_on();
+
+        match self.safety.turn_on(self.ticker.get_ticks()) {
+            Ok(()) => {
+                self.denied = None;
+                self.laser.set_high();
+                // Only SAFETY_CHECK feeds the watchdog while the laser stays on,
+                // aiming at a new position must not postpone or replace it.
+                if !was_on {
+                    SAFETY_CHECK.call_at(self.ticker.now() + SAFETY_CHECK_INTERVAL);
+                    watchdog::set_active(Subsystem::Targeting, true);
+                }
+            }
+            Err(denied) => {
+                if self.denied != Some(denied) {
+                    rprintln!("laser denied: {:?}", denied);
+                    self.denied = Some(denied);
+                }
+                self.laser.set_low();
+            }
+        }
+    }
+
+    fn disable_laser(&mut self) {
         self.laser.set_low();
-        self.last_lock = self.ticker.now();
+        self.safety.turn_off();
+        SAFETY_CHECK.cancel();
+        watchdog::set_active(Subsystem::Targeting, false);
+    }
+
+    // Force the laser off if it may not stay on anymore.
+    fn check_safety(&mut self) {
+        watchdog::heartbeat(Subsystem::Targeting);
+
+        match self.safety.update(self.ticker.get_ticks()) {
+            Ok(()) if self.safety.is_on() => {
+                SAFETY_CHECK.call_at(self.ticker.now() + SAFETY_CHECK_INTERVAL);
+            }
+            Ok(()) => watchdog::set_active(Subsystem::Targeting, false),
+            Err(denied) => {
+                // Target stays locked, the policy refuses to turn the laser back on.
+                rprintln!("laser forced off: {:?}", denied);
+                self.denied = Some(denied);
+                self.laser.set_low();
+                SAFETY_CHECK.cancel();
+                watchdog::set_active(Subsystem::Targeting, false);
+            }
+        }
+    }
+
+    fn laser_off(&mut self) {
+        self.disable_laser();
+        self.lock = None;
+        self.track.clear();
+        self.last_lock = self.ticker.now64();
 
         self.audio.play(Sound::ContactLost);
-        TARGET_LOST.call_at(self.ticker.now() + TARGET_LOST_DELAY);
+        TARGET_LOST.call_at(self.ticker.now() + self.target_lost_delay);
     }
 
-    fn set_lock(&mut self, start_position: u16, end_position: u16) -> Result<(), Error> {
-        self.target_state = TargetState::Lock {
-            start_position,
-            end_position,
+    fn play_quip(&mut self) {
+        self.audio.set_idle_event(None);
+
+        // Skip it if the target is gone or something else is playing already.
+        if self.lock.is_some() && self.audio.is_idle() {
+            self.audio.play(Sound::Quip);
+        }
+    }
+
+    fn target_lost(&mut self) -> Result<(), Error> {
+        self.audio.play(Sound::TargetLost);
+        self.start_patrol()
+    }
+
+    // Sweep the middle of the servo range, starting from its center.
+    fn start_patrol(&mut self) -> Result<(), Error> {
+        if self.is_paused() || self.patrol_arc == 0 {
+            return Ok(());
+        }
+
+        let last_step = u32::from(self.total_steps - 1);
+        let width = (last_step * u32::from(self.patrol_arc) / 100) as u16;
+        let low = (last_step as u16 - width) / 2;
+
+        self.patrol = Some(Patrol {
+            position: low + width / 2,
+            low,
+            high: low + width,
+            forward: true,
+        });
+        self.patrol_step()
+    }
+
+    fn stop_patrol(&mut self) {
+        if self.patrol.take().is_some() {
+            PATROL.cancel();
+        }
+    }
+
+    fn patrol_step(&mut self) -> Result<(), Error> {
+        let Some(patrol) = self.patrol.as_mut() else {
+            return Ok(());
         };
 
-        let low_side = min(start_position, end_position);
-        let high_side = max(start_position, end_position);
+        let delay = match patrol.forward {
+            true if patrol.position < patrol.high => {
+                patrol.position += 1;
+                PATROL_STEP_TIME
+            }
+            false if patrol.position > patrol.low => {
+                patrol.position -= 1;
+                PATROL_STEP_TIME
+            }
+            _ => {
+                patrol.forward = !patrol.forward;
+                Duration::millis(self.random.u32(PATROL_DWELL_MS))
+            }
+        };
 
-        let servo_position = Ratio::new(low_side + (high_side - low_side) / 2, self.total_steps);
+        self.servo
+            .set(Ratio::new(patrol.position, self.total_steps))?;
+        PATROL.call_at(self.ticker.now() + delay);
+
+        Ok(())
+    }
+
+    fn set_lock(&mut self, target: Span) -> Result<(), Error> {
+        if self.lock.is_none() {
+            if self.ticker.now64() - self.last_lock >= Duration64::from(TARGET_ACQUIRED_INTERVAL) {
+                // Quip follows once the announcement is over.
+                self.audio.set_idle_event(Some(&PLAY_QUIP));
+                self.audio.play(Sound::TargetAcquired);
+            } else {
+                self.audio.play(Sound::ContactRestored);
+            }
+        }
+
+        self.lock = Some(target);
+
+        let servo_position = Ratio::new(self.aim_position(&target), self.total_steps);
 
         self.servo.set(servo_position)?;
-        self.laser.set_high();
+        self.enable_laser();
 
-        LASER_OFF.call_at(self.ticker.now() + LASER_OFF_DELAY);
+        LASER_OFF.call_at(self.ticker.now() + self.laser_off_delay);
         TARGET_LOST.cancel();
 
         Ok(())
     }
 
-    fn process_contact(&mut self, position: u16) -> Result<(), Error> {
-        self.led.set_high();
+    // Aim ahead of a moving target, at its middle if the movement is unclear.
+    fn aim_position(&self, target: &Span) -> u16 {
+        let center = target.center();
+        let Some(offset) = lead_offset(&self.track, self.lead_time.to_millis()) else {
+            return center;
+        };
+
+        (center as i32 + offset).clamp(0, self.total_steps as i32 - 1) as u16
+    }
+
+    // Remember the position of the locked target, start over when it changes.
+    fn update_track(&mut self, target: &Span) {
+        if !self.lock.is_some_and(|lock| lock.overlaps(target)) {
+            self.track.clear();
+        }
+
+        if self.track.is_full() {
+            self.track.pop_front();
+        }
+        // Can't fail, there is space.
+        let _ = self.track.push_back((target.seen, target.center()));
+    }
+
+    // Save finished contact as a target if it is wide enough.
+    fn close_contact(&mut self) {
+        let Some(contact) = self.contact.take() else {
+            return;
+        };
+
+        let mut span = contact.span;
+        if !self.is_wide_enough(&span) {
+            return;
+        }
+
+        // Sweep speed is constant, so the center was scanned halfway.
+        span.seen = span.seen + (contact.last_seen - span.seen) / 2;
+
+        if let Some(previous) = self.previous_targets.iter().find(|t| t.overlaps(&span)) {
+            span.sweeps = previous.sweeps.saturating_add(1);
+        }
+
+        if self.targets.push(span).is_err() {
+            rprintln!("too many targets, ignoring {:?}", span);
+        }
+    }
 
-        match self.target_state {
-            TargetState::NoContact => {
-                self.target_state = TargetState::EarlyContact {
-                    start_position: position,
-                };
+    // Pick the best target, but don't jump away from the locked one
+    // until another target is preferred for several sweeps.
+    fn select_target(&mut self) -> Result<(), Error> {
+        let best = self.targets.iter().copied().reduce(|best, target| {
+            if target.is_better(&best, self.selection_policy) {
+                target
+            } else {
+                best
             }
-            TargetState::EarlyContact { start_position } => {
-                let low_side = min(start_position, position);
-                let high_side = max(start_position, position);
-
-                if high_side - low_side == MIN_TARGET_LOCK_RANGE {
-                    if self.ticker.now() - self.last_lock >= TARGET_ACQUIRED_INTERVAL {
-                        self.audio.play(Sound::TargetAcquired);
-                    } else {
-                        self.audio.play(Sound::ContactRestored);
-                    }
-                    self.set_lock(start_position, position)?;
-                }
+        });
+
+        let Some(best) = best else {
+            // Nothing in sight, laser goes off after delay.
+            self.challenger_sweeps = 0;
+            return Ok(());
+        };
+
+        let current = self
+            .lock
+            .and_then(|lock| self.targets.iter().find(|t| t.overlaps(&lock)).copied());
+
+        let target = match current {
+            Some(current)
+                if current != best && self.challenger_sweeps + 1 < TARGET_SWITCH_SWEEPS =>
+            {
+                self.challenger_sweeps += 1;
+                current
+            }
+            _ => {
+                self.challenger_sweeps = 0;
+                best
             }
-            TargetState::Lock {
-                start_position,
-                end_position: _,
-            } => {
-                self.set_lock(start_position, position)?;
+        };
+
+        self.update_track(&target);
+        self.set_lock(target)
+    }
+
+    fn process_contact(&mut self, position: u16, distance: u16) -> Result<(), Error> {
+        self.led.set_high();
+        self.stop_patrol();
+
+        let now = self.ticker.now();
+        let contact = match self.contact {
+            None => Contact {
+                span: Span::new(position, distance, now),
+                last_position: position,
+                last_seen: now,
+            },
+            Some(mut contact) => {
+                contact.span.add(position, distance);
+                contact.last_position = position;
+                contact.last_seen = now;
+                contact
+            }
+        };
+        self.contact = Some(contact);
+
+        if self.is_wide_enough(&contact.span) {
+            match self.lock {
+                // First target, lock immediately.
+                None => self.set_lock(contact.span)?,
+                // Follow the locked target.
+                Some(lock) if lock.overlaps(&contact.span) => self.set_lock(contact.span)?,
+                // Another target, it is considered at the end of sweep.
+                Some(_) => {}
             }
         }
 
@@ -130,33 +592,28 @@ impl State {
     fn process_no_contact(&mut self, position: u16) -> Result<(), Error> {
         self.led.set_low();
 
-        match self.target_state {
-            TargetState::NoContact => {}
-            TargetState::EarlyContact { start_position: _ } => {
-                self.target_state = TargetState::NoContact;
-            }
-            TargetState::Lock {
-                start_position,
-                end_position,
-            } => {
-                let lock_break = if start_position < end_position {
-                    position - end_position >= MAX_TARGET_BREAK_RANGE
-                } else {
-                    end_position - position >= MAX_TARGET_BREAK_RANGE
-                };
-
-                if lock_break {
-                    self.target_state = TargetState::NoContact;
-                }
+        if let Some(contact) = self.contact {
+            // Short contacts are noise, wide ones tolerate small gaps.
+            // Coarse sweeps can't tell gaps shorter than the stride.
+            let contact_break = !self.is_wide_enough(&contact.span)
+                || position.abs_diff(contact.last_position)
+                    >= max(self.max_target_break_range, self.stride);
+
+            if contact_break {
+                self.close_contact();
             }
         }
 
         Ok(())
     }
 
-    fn report(&mut self, position: u16, contact: bool) -> Result<(), Error> {
+    fn report(&mut self, position: u16, distance: u16, contact: bool) -> Result<(), Error> {
+        if self.is_paused() {
+            return Ok(());
+        }
+
         if contact {
-            self.process_contact(position)
+            self.process_contact(position, distance)
         } else {
             self.process_no_contact(position)
         }
@@ -193,9 +650,11 @@ impl StaticState {
 // Therefore, no locking is necessary.
 unsafe impl Sync for StaticState {}
 
+#[derive(Clone, Copy)]
 pub struct Targeting;
 
 impl Targeting {
+    #[allow(clippy::too_many_arguments)]
     pub fn new(
         ticker: Ticker,
         event_queue: &mut EventQueue<'_, 'static>,
@@ -204,44 +663,121 @@ impl Targeting {
         servo: LaserServo,
         total_steps: u16,
         audio: Audio,
+        random: Rng,
     ) -> Result<Self, Error> {
         event_queue.bind(&LASER_OFF);
         event_queue.bind(&TARGET_LOST);
+        event_queue.bind(&SAFETY_CHECK);
+        event_queue.bind(&PATROL);
+        event_queue.bind(&PLAY_QUIP);
 
-        STATE.set(State::init(ticker, led, laser, servo, total_steps, audio)?);
+        STATE.set(State::init(
+            ticker,
+            led,
+            laser,
+            servo,
+            total_steps,
+            audio,
+            random,
+        )?);
 
         Ok(Targeting {})
     }
 
     // NOT interrupt-safe
-    pub fn reset(&self) -> Result<(), Error> {
+    pub fn apply_settings(&self, settings: &Settings) -> Result<(), Error> {
         STATE.with(|state| {
-            state.reset();
+            state.apply_settings(settings);
             Ok(())
         })
     }
 
+    // Laser stays off while the turret is picked up.
     // NOT interrupt-safe
-    pub fn report(&self, position: u16, contact: bool) -> Result<(), Error> {
-        STATE.with(|state| state.report(position, contact))
+    pub fn set_picked_up(&self, picked_up: bool) -> Result<(), Error> {
+        STATE.with(|state| {
+            state.set_picked_up(picked_up);
+            Ok(())
+        })
     }
-}
 
-static STATE: StaticState = StaticState::new();
+    // Laser stays off while the button is held.
+    // NOT interrupt-safe
+    pub fn set_button_held(&self, held: bool) -> Result<(), Error> {
+        STATE.with(|state| {
+            state.set_button_held(held);
+            Ok(())
+        })
+    }
+
+    // NOT interrupt-safe
+    pub fn is_locked_on(&self, position: u16) -> Result<bool, Error> {
+        STATE.with(|state| Ok(state.is_locked_on(position)))
+    }
 
-static LASER_OFF: Event = Event::new(&|| {
-    STATE
-        .with(|state| {
-            state.laser_off();
+    // Called by ranging before each sweep.
+    // NOT interrupt-safe
+    pub fn set_plan(&self, plan: StepPlan) -> Result<(), Error> {
+        STATE.with(|state| {
+            state.set_plan(plan);
             Ok(())
         })
-        .unwrap()
-});
-static TARGET_LOST: Event = Event::new(&|| {
-    STATE
-        .with(|state| {
-            state.audio.play(Sound::TargetLost);
+    }
+
+    // NOT interrupt-safe
+    pub fn reset(&self) -> Result<(), Error> {
+        STATE.with(|state| state.reset())
+    }
+
+    // NOT interrupt-safe
+    pub fn pause(&self, reason: PauseReason) -> Result<(), Error> {
+        STATE.with(|state| {
+            state.pause(reason);
             Ok(())
         })
-        .unwrap()
+    }
+
+    // NOT interrupt-safe
+    pub fn resume(&self, reason: PauseReason) -> Result<(), Error> {
+        STATE.with(|state| {
+            state.resume(reason);
+            Ok(())
+        })
+    }
+
+    // NOT interrupt-safe
+    pub fn shutdown(&self) -> Result<(), Error> {
+        STATE.with(|state| {
+            state.shutdown();
+            Ok(())
+        })
+    }
+
+    // NOT interrupt-safe
+    pub fn report(&self, position: u16, distance: u16, contact: bool) -> Result<(), Error> {
+        STATE.with(|state| state.report(position, distance, contact))
+    }
+}
+
+static STATE: StaticState = StaticState::new();
+
+static LASER_OFF: Event = Event::new_fallible(&|| {
+    STATE.with(|state| {
+        state.laser_off();
+        Ok(())
+    })
+});
+static TARGET_LOST: Event = Event::new_fallible(&|| STATE.with(|state| state.target_lost()));
+static SAFETY_CHECK: Event = Event::new_fallible(&|| {
+    STATE.with(|state| {
+        state.check_safety();
+        Ok(())
+    })
+});
+static PATROL: Event = Event::new_fallible(&|| STATE.with(|state| state.patrol_step()));
+static PLAY_QUIP: Event = Event::new_fallible(&|| {
+    STATE.with(|state| {
+        state.play_quip();
+        Ok(())
+    })
 });
@@ -0,0 +1,125 @@
+// Event loop health monitor. The independent watchdog resets the MCU unless it
+// is fed, and it is only fed while every active subsystem keeps making progress.
+// A hung event loop stops the check itself, a stuck subsystem stops its heartbeats.
+
+use crate::board::Watchdog;
+use crate::error::Error;
+use crate::event_queue::{Event, EventQueue, ExtEvent};
+use crate::system_time::{Duration, Ticker};
+
+use core::cell::{Cell, RefCell};
+use critical_section::Mutex;
+use rtt_target::rprintln;
+
+// Every active subsystem must beat at least once per interval.
+// Watchdog timeout in board.rs is twice as long.
+const CHECK_INTERVAL: Duration = Duration::secs(2);
+
+#[derive(Clone, Copy, Debug)]
+pub enum Subsystem {
+    // Active while scanning, beats on every measurement.
+    Ranging = 1,
+    // Active while playing, beats on every buffer.
+    Audio = 2,
+    // Active while the laser is on, beats on every safety check.
+    Targeting = 4,
+}
+
+const ALL: [Subsystem; 3] = [Subsystem::Ranging, Subsystem::Audio, Subsystem::Targeting];
+
+// Bit masks of subsystems.
+static ACTIVE: Mutex<Cell<u8>> = Mutex::new(Cell::new(0));
+static BEATS: Mutex<Cell<u8>> = Mutex::new(Cell::new(0));
+
+// Record progress of a subsystem.
+// This function is interrupt-safe.
+pub fn heartbeat(subsystem: Subsystem) {
+    critical_section::with(|cs| {
+        let beats = BEATS.borrow(cs);
+        beats.set(beats.get() | subsystem as u8);
+    });
+}
+
+// Subsystems are only checked while they have work to do.
+// This function is interrupt-safe.
+pub fn set_active(subsystem: Subsystem, active: bool) {
+    critical_section::with(|cs| {
+        let mask = ACTIVE.borrow(cs);
+        if active {
+            mask.set(mask.get() | subsystem as u8);
+            // Give the subsystem a full interval for the first beat.
+            let beats = BEATS.borrow(cs);
+            beats.set(beats.get() | subsystem as u8);
+        } else {
+            mask.set(mask.get() & !(subsystem as u8));
+        }
+    });
+}
+
+struct State {
+    ticker: Ticker,
+    watchdog: Watchdog,
+}
+
+impl State {
+    fn check(&mut self) -> Result<(), Error> {
+        let (active, beats) =
+            critical_section::with(|cs| (ACTIVE.borrow(cs).get(), BEATS.borrow(cs).replace(0)));
+
+        let stalled = active & !beats;
+        if stalled == 0 {
+            self.watchdog.feed();
+        } else {
+            for subsystem in ALL {
+                if stalled & subsystem as u8 != 0 {
+                    rprintln!("{:?} stalled, waiting for watchdog reset", subsystem);
+                }
+            }
+        }
+
+        CHECK_WATCHDOG.call_at(self.ticker.now() + CHECK_INTERVAL);
+
+        Ok(())
+    }
+}
+
+struct StaticState {
+    state: RefCell<Option<State>>,
+}
+
+impl StaticState {
+    const fn new() -> Self {
+        Self {
+            state: RefCell::new(None),
+        }
+    }
+
+    fn set(&self, state: State) {
+        *self.state.borrow_mut() = Some(state);
+    }
+
+    fn with<F, R>(&self, f: F) -> Result<R, Error>
+    where
+        F: Fn(&mut State) -> Result<R, Error>,
+    {
+        let mut stateref = self.state.borrow_mut();
+        let state = stateref.as_mut().ok_or(Error::Uninitialized)?;
+
+        f(state)
+    }
+}
+
+// STATE is only accessed from the main thread via EventQueue.
+// Therefore, no locking is necessary.
+unsafe impl Sync for StaticState {}
+
+static STATE: StaticState = StaticState::new();
+
+static CHECK_WATCHDOG: Event = Event::new_fallible(&|| STATE.with(|state| state.check()));
+
+pub fn start(ticker: Ticker, event_queue: &mut EventQueue<'_, 'static>, watchdog: Watchdog) {
+    event_queue.bind(&CHECK_WATCHDOG);
+
+    STATE.set(State { ticker, watchdog });
+    CHECK_WATCHDOG.call();
+}
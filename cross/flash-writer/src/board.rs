@@ -4,7 +4,6 @@ use crate::error::Error;
 
 use stm32f1xx_hal::crc::Crc;
 use stm32f1xx_hal::device::USART2;
-use stm32f1xx_hal::dma::dma1::C6;
 use stm32f1xx_hal::pac;
 use stm32f1xx_hal::prelude::*;
 use stm32f1xx_hal::serial::{Config, Rx, Tx};
@@ -14,14 +13,12 @@ pub use board::{Button, Led, SpiBus, SpiCs, Uart};
 pub type SpiMemory = spi_memory::series25::Flash<SpiBus, SpiCs>;
 pub type SerTx = Tx<USART2>;
 pub type SerRx = Rx<USART2>;
-pub type SerDma = C6;
 
 pub struct Board {
     pub button: Button,
     pub led: Led,
     pub tx: SerTx,
     pub rx: SerRx,
-    pub dma: SerDma,
     pub memory: SpiMemory,
     pub crc: Crc,
 }
@@ -34,7 +31,6 @@ impl Board {
             w.dbg_standby().set_bit();
             w.dbg_stop().set_bit()
         });
-        dp.RCC.ahbenr.modify(|_, w| w.dma1en().enabled());
 
         // Configure the clock.
         let mut flash = dp.FLASH.constrain();
@@ -43,9 +39,6 @@ impl Board {
 
         let mut afio = dp.AFIO.constrain();
 
-        // Acquire DMA
-        let dma1 = dp.DMA1.split();
-
         // Acquire the GPIO peripherals.
         let mut gpioa = dp.GPIOA.split();
         let mut gpiob = dp.GPIOB.split();
@@ -92,7 +85,6 @@ impl Board {
             led,
             tx,
             rx,
-            dma: dma1.6,
             memory,
             crc,
         })
@@ -3,143 +3,276 @@
 
 mod board;
 mod error;
+mod progress;
 
-use crate::board::Board;
+use crate::board::{Board, SerTx, SpiMemory};
+use crate::progress::{Progress, FLASH_SIZE, MAX_IMAGE_LEN, SECTOR_LEN};
 
 use bytes::Buf;
 use core::cmp::min;
 use cortex_m::asm::wfi;
 use cortex_m_rt::entry;
+use frame::{max_encoded_len, Frame, Receiver};
 use nb::block;
 use rtt_target::{rprintln, rtt_init_print};
 use spi_memory::BlockDevice;
 use spi_memory::Read;
-use stm32f1xx_hal::dma::ReadDma;
+use stm32f1xx_hal::crc::Crc;
 use stm32f1xx_hal::pac;
 
 use panic_probe as _;
 
 const BLOCK_LEN: usize = 4096;
-static mut BLOCK: [u8; BLOCK_LEN + 4] = [0; BLOCK_LEN + 4];
+static mut BLOCK: [u8; BLOCK_LEN] = [0; BLOCK_LEN];
 
-#[entry]
-fn main() -> ! {
-    rtt_init_print!();
+// Largest request is a block write: block index and data.
+const MAX_REQUEST_LEN: usize = max_encoded_len(4 + BLOCK_LEN);
+// Largest reply is a read: CRC and data. Reads are shorter than a block to save RAM.
+const READ_LEN: usize = 1024;
+const MAX_REPLY_PAYLOAD: usize = 4 + READ_LEN;
 
-    // let cp = pac::CorePeripherals::take().unwrap();
-    let dp = pac::Peripherals::take().unwrap();
+// Reply kinds, with the sequence number of the request.
+const ACK: u8 = 42;
+// Request was corrupted or can't be executed, host sends it again.
+const NACK: u8 = 88;
 
-    let mut board = Board::new(dp).unwrap();
-    let mut rx = board.rx;
-    let mut tx = board.tx;
+// Requests, each one is answered with ACK or NACK. Requests are idempotent,
+// so they are safe to repeat if the reply was lost.
+// Image length and CRC, u32be. Reply is block length, u16be,
+// and number of blocks written previously, u32be.
+const CMD_START: u8 = b'I';
+// Block to start from, u32be. Progress log is reset when starting from 0.
+const CMD_BEGIN: u8 = b'B';
+// Query CRC of the block currently in flash. Block index, u32be, reply is CRC, u32be.
+const CMD_QUERY_CRC: u8 = b'Q';
+// Keep the block as is. Block index, u32be.
+const CMD_SKIP: u8 = b'S';
+// Write block. Block index, u32be, followed by block data.
+const CMD_WRITE: u8 = b'W';
+// Transfer is complete.
+const CMD_DONE: u8 = b'D';
+// Read flash, doesn't need an image. Address, u32be, and length, u16be,
+// both multiples of 4, length up to READ_LEN. Reply is CRC, u32be, followed by data.
+const CMD_READ: u8 = b'R';
 
-    rprintln!("Press button to start");
-    while board.button.is_low() {}
+struct Image {
+    len: usize,
+    num_blocks: usize,
+    progress: Progress,
+}
 
-    rprintln!("Erasing flash...");
-    board.memory.erase_all().unwrap();
-    rprintln!("Flash erased");
-
-    // Read total data length, u32be
-    let mut total_len_buf = [0; 4];
-    for byte in total_len_buf.iter_mut() {
-        loop {
-            if let Ok(b) = block!(rx.read()) {
-                *byte = b;
-                break;
-            }
-        }
+impl Image {
+    // Length of the block, if the index is valid.
+    fn block_len(&self, block: usize) -> Option<usize> {
+        (block < self.num_blocks).then(|| min(BLOCK_LEN, self.len - block * BLOCK_LEN))
     }
-    let total_len = u32::from_be_bytes(total_len_buf) as usize;
-    rprintln!("Expected image length {} bytes", total_len);
+}
 
-    if total_len % 4 != 0 {
-        panic!("Image length must be a multiple of 4");
+fn crc_of(crc: &mut Crc, mut data: &[u8]) -> u32 {
+    crc.reset();
+    while data.remaining() > 0 {
+        crc.write(data.get_u32());
     }
+    crc.read()
+}
+
+fn flash_crc(memory: &mut SpiMemory, crc: &mut Crc, address: usize, len: usize) -> u32 {
+    let buffer = unsafe { &mut BLOCK[..len] };
+    memory.read(address as u32, buffer).unwrap();
+
+    crc_of(crc, buffer)
+}
+
+fn u32_at(payload: &[u8], offset: usize) -> Option<u32> {
+    let bytes = payload.get(offset..offset + 4)?;
+    Some(u32::from_be_bytes(bytes.try_into().unwrap()))
+}
+
+fn send_reply(tx: &mut SerTx, seq: u8, kind: u8, payload: &[u8]) {
+    let mut buffer = [0; max_encoded_len(MAX_REPLY_PAYLOAD)];
+    let len = Frame::new(seq, kind, payload).encode(&mut buffer).unwrap();
 
-    // Send block length, u16be
-    tx.bwrite_all((BLOCK_LEN as u16).to_be_bytes().as_ref())
-        .unwrap();
+    tx.bwrite_all(&buffer[..len]).unwrap();
+}
+
+// Execute a request, returns reply payload length or None if it can't be executed.
+fn execute(
+    board: &mut Board,
+    image: &mut Option<Image>,
+    request: &Frame,
+    reply: &mut [u8; MAX_REPLY_PAYLOAD],
+) -> Option<usize> {
+    let payload = request.payload;
+
+    if request.kind == CMD_READ {
+        let address = u32_at(payload, 0)? as usize;
+        let len = usize::from(u16::from_be_bytes(payload.get(4..6)?.try_into().unwrap()));
+        if address % 4 != 0 || len % 4 != 0 || len > READ_LEN || address + len > FLASH_SIZE {
+            rprintln!("Invalid read of {} bytes at {:x}", len, address);
+            return None;
+        }
 
-    let mut rxdma = rx.with_dma(board.dma);
-    let mut current_block = 0;
-    while current_block * BLOCK_LEN < total_len {
-        let bytes_left = total_len - current_block * BLOCK_LEN;
-        let expected_bytes = min(BLOCK_LEN, bytes_left);
+        let data = &mut reply[4..4 + len];
+        board.memory.read(address as u32, data).unwrap();
+        let crc = crc_of(&mut board.crc, data);
+
+        reply[0..4].copy_from_slice(&crc.to_be_bytes());
+        return Some(4 + len);
+    }
+
+    if request.kind == CMD_START {
+        // Read total data length and image CRC
+        let total_len = u32_at(payload, 0)? as usize;
+        let image_crc = u32_at(payload, 4)?;
         rprintln!(
-            "Reading block {} of {} bytes",
-            current_block,
-            expected_bytes
+            "Expected image length {} bytes, crc {:x}",
+            total_len,
+            image_crc
         );
 
-        let buffer = unsafe { &mut BLOCK[..expected_bytes + 4] };
-        // Read block from serial
-        let (bytes, retrx) = rxdma.read(buffer).wait();
-        rxdma = retrx;
-        // Verify CRC
-        let expected_crc = u32::from_be_bytes(bytes[expected_bytes..].try_into().unwrap());
-        let mut data_bytes = &bytes[..expected_bytes];
-
-        board.crc.reset();
-        while data_bytes.remaining() > 0 {
-            board.crc.write(data_bytes.get_u32());
+        if total_len % 4 != 0 {
+            rprintln!("Image length must be a multiple of 4");
+            return None;
         }
-        let actual_crc = board.crc.read();
-        if actual_crc != expected_crc {
-            // Send NACK
-            block!(tx.write(88)).unwrap();
-            panic!(
-                "crc mismatch: received {:x}, calculated {:x}",
-                expected_crc, actual_crc
-            );
+        if total_len > MAX_IMAGE_LEN {
+            rprintln!("Image is too large");
+            return None;
         }
 
-        // Write to flash
-        rprintln!("Writing block");
-        board
-            .memory
-            .write_bytes(
-                (current_block * BLOCK_LEN) as u32,
-                &mut bytes[..expected_bytes],
-            )
+        let num_blocks = total_len.div_ceil(BLOCK_LEN);
+        let progress = Progress::new(total_len as u32, image_crc);
+        let written_blocks = progress
+            .written_blocks(&mut board.memory, num_blocks)
             .unwrap();
+        rprintln!("{} blocks written previously", written_blocks);
 
-        // Send confirmation
-        block!(tx.write(42)).unwrap();
+        *image = Some(Image {
+            len: total_len,
+            num_blocks,
+            progress,
+        });
 
-        current_block += 1;
+        reply[0..2].copy_from_slice(&(BLOCK_LEN as u16).to_be_bytes());
+        reply[2..6].copy_from_slice(&(written_blocks as u32).to_be_bytes());
+        return Some(6);
     }
 
-    rprintln!("Writes done");
-    board.crc.reset();
+    let Some(image) = image else {
+        rprintln!("No image started");
+        return None;
+    };
 
-    let mut current_block = 0;
-    while current_block * BLOCK_LEN < total_len {
-        let bytes_left = total_len - current_block * BLOCK_LEN;
-        let expected_bytes = min(BLOCK_LEN, bytes_left);
-        rprintln!(
-            "Reading block {} of {} bytes",
-            current_block,
-            expected_bytes
-        );
+    match request.kind {
+        CMD_BEGIN => {
+            let start_block = u32_at(payload, 0)?;
+            if start_block == 0 {
+                image.progress.reset(&mut board.memory).unwrap();
+            } else {
+                rprintln!("Resuming from block {}", start_block);
+            }
 
-        let buffer = unsafe { &mut BLOCK[..expected_bytes] };
-        board
-            .memory
-            .read((current_block * BLOCK_LEN) as u32, buffer)
-            .unwrap();
+            Some(0)
+        }
+        CMD_QUERY_CRC => {
+            let block = u32_at(payload, 0)? as usize;
+            let len = image.block_len(block)?;
+
+            let crc = flash_crc(&mut board.memory, &mut board.crc, block * BLOCK_LEN, len);
+            rprintln!("Block {} crc {:x}", block, crc);
+
+            reply[0..4].copy_from_slice(&crc.to_be_bytes());
+            Some(4)
+        }
+        CMD_SKIP => {
+            let block = u32_at(payload, 0)? as usize;
+            image.block_len(block)?;
 
-        let mut data_bytes: &[u8] = buffer;
+            rprintln!("Skipping block {}", block);
+            image
+                .progress
+                .mark_written(&mut board.memory, block)
+                .unwrap();
 
-        while data_bytes.remaining() > 0 {
-            board.crc.write(data_bytes.get_u32());
+            Some(0)
         }
+        CMD_WRITE => {
+            let block = u32_at(payload, 0)? as usize;
+            let len = image.block_len(block)?;
+            let data = &payload[4..];
+            if data.len() != len {
+                rprintln!("Block {} has {} bytes, expected {}", block, data.len(), len);
+                return None;
+            }
+
+            rprintln!("Writing block {} of {} bytes", block, len);
+            let buffer = unsafe { &mut BLOCK[..len] };
+            buffer.copy_from_slice(data);
 
-        current_block += 1;
+            let address = block * BLOCK_LEN;
+            board
+                .memory
+                .erase_sectors(address as u32, BLOCK_LEN / SECTOR_LEN)
+                .unwrap();
+            board.memory.write_bytes(address as u32, buffer).unwrap();
+            image
+                .progress
+                .mark_written(&mut board.memory, block)
+                .unwrap();
+
+            Some(0)
+        }
+        CMD_DONE => {
+            rprintln!("All done");
+            Some(0)
+        }
+        _ => {
+            rprintln!("Unknown command {}", request.kind);
+            None
+        }
     }
+}
+
+#[entry]
+fn main() -> ! {
+    rtt_init_print!();
 
-    rprintln!("Whole drive CRC: {:x}", board.crc.read());
-    rprintln!("All done");
+    // let cp = pac::CorePeripherals::take().unwrap();
+    let dp = pac::Peripherals::take().unwrap();
+
+    let mut board = Board::new(dp).unwrap();
+
+    rprintln!("Press button to start");
+    while board.button.is_low() {}
+
+    let mut receiver = Receiver::<MAX_REQUEST_LEN>::new();
+    let mut image = None;
+    let mut done = false;
+
+    while !done {
+        // Serial errors lose bytes, the frame checksum catches that.
+        let Ok(byte) = block!(board.rx.read()) else {
+            continue;
+        };
+
+        let request = match receiver.push(byte) {
+            None => continue,
+            Some(Ok(request)) => request,
+            Some(Err(err)) => {
+                rprintln!("Bad frame: {:?}", err);
+                send_reply(&mut board.tx, 0, NACK, &[]);
+                continue;
+            }
+        };
+
+        let mut reply = [0; MAX_REPLY_PAYLOAD];
+        match execute(&mut board, &mut image, &request, &mut reply) {
+            Some(len) => {
+                send_reply(&mut board.tx, request.seq, ACK, &reply[..len]);
+                done = request.kind == CMD_DONE;
+            }
+            None => send_reply(&mut board.tx, request.seq, NACK, &[]),
+        }
+    }
 
     loop {
         wfi();
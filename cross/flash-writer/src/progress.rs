@@ -0,0 +1,93 @@
+#![deny(unsafe_code)]
+
+// Transfer progress log, kept in the last flash sector.
+// Header identifies the image, followed by one byte per block.
+// Erased flash reads as 0xFF, written blocks are marked by programming 0x00,
+// which doesn't need another erase.
+
+use crate::board::SpiMemory;
+use crate::error::Error;
+
+use spi_memory::{BlockDevice, Read};
+
+pub const FLASH_SIZE: usize = 2 * 1024 * 1024;
+pub const SECTOR_LEN: usize = 4096;
+
+// Images must not overwrite the turret baseline and settings sectors and the progress log.
+pub const MAX_IMAGE_LEN: usize = FLASH_SIZE - 3 * SECTOR_LEN;
+
+const PROGRESS_ADDRESS: usize = FLASH_SIZE - SECTOR_LEN;
+const MAGIC: u32 = 0x5052_4f47; // "PROG"
+const HEADER_LEN: usize = 12;
+const WRITTEN: u8 = 0;
+
+pub struct Progress {
+    image_len: u32,
+    image_crc: u32,
+}
+
+impl Progress {
+    pub fn new(image_len: u32, image_crc: u32) -> Self {
+        Progress {
+            image_len,
+            image_crc,
+        }
+    }
+
+    // Count blocks of this image already written in previous sessions.
+    pub fn written_blocks(
+        &self,
+        memory: &mut SpiMemory,
+        num_blocks: usize,
+    ) -> Result<usize, Error> {
+        let mut header = [0; HEADER_LEN];
+        memory.read(PROGRESS_ADDRESS as u32, &mut header)?;
+
+        if header != self.header() {
+            return Ok(0);
+        }
+
+        let mut written = 0;
+        let mut flags = [0; 64];
+        while written < num_blocks {
+            let len = flags.len().min(num_blocks - written);
+            memory.read(
+                (PROGRESS_ADDRESS + HEADER_LEN + written) as u32,
+                &mut flags[..len],
+            )?;
+
+            match flags[..len].iter().position(|&flag| flag != WRITTEN) {
+                Some(pos) => return Ok(written + pos),
+                None => written += len,
+            }
+        }
+
+        Ok(written)
+    }
+
+    // Start a new log for this image.
+    pub fn reset(&self, memory: &mut SpiMemory) -> Result<(), Error> {
+        memory.erase_sectors(PROGRESS_ADDRESS as u32, 1)?;
+        memory.write_bytes(PROGRESS_ADDRESS as u32, &mut self.header())?;
+
+        Ok(())
+    }
+
+    pub fn mark_written(&self, memory: &mut SpiMemory, block: usize) -> Result<(), Error> {
+        memory.write_bytes(
+            (PROGRESS_ADDRESS + HEADER_LEN + block) as u32,
+            &mut [WRITTEN],
+        )?;
+
+        Ok(())
+    }
+
+    fn header(&self) -> [u8; HEADER_LEN] {
+        let mut header = [0; HEADER_LEN];
+        header[0..4].copy_from_slice(&MAGIC.to_be_bytes());
+        header[4..8].copy_from_slice(&self.image_len.to_be_bytes());
+        header[8..12].copy_from_slice(&self.image_crc.to_be_bytes());
+
+        header
+    }
+}
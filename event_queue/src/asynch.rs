@@ -0,0 +1,183 @@
+// Adapter for running the queue as a task of an async executor, e.g. Embassy
+// or RTIC 2 software tasks. The executor provides the clock and the timer,
+// the queue wakes its task when an event is posted.
+//
+// Posting an event wakes the single task registered here, so only one queue
+// per program may run asynchronously.
+
+use crate::{time_until, EventQueue, TICKS};
+
+use core::cell::{Cell, RefCell};
+use core::future::{poll_fn, Future};
+use core::pin::pin;
+use core::task::{Poll, Waker};
+use critical_section::{CriticalSection, Mutex};
+
+// Set when an event is posted, cleared by the waiting task.
+static POSTED: Mutex<Cell<bool>> = Mutex::new(Cell::new(false));
+static WAKER: Mutex<RefCell<Option<Waker>>> = Mutex::new(RefCell::new(None));
+
+// Called with the event state updated, wakes the queue task.
+pub(crate) fn notify_posted(cs: CriticalSection) {
+    POSTED.borrow(cs).set(true);
+
+    if let Some(waker) = WAKER.borrow_ref(cs).as_ref() {
+        waker.wake_by_ref();
+    }
+}
+
+// Resolves when any event is posted after the last check.
+async fn posted() {
+    poll_fn(|cx| {
+        critical_section::with(|cs| {
+            if POSTED.borrow(cs).replace(false) {
+                return Poll::Ready(());
+            }
+
+            let mut waker = WAKER.borrow_ref_mut(cs);
+            match waker.as_ref() {
+                Some(registered) if registered.will_wake(cx.waker()) => {}
+                _ => *waker = Some(cx.waker().clone()),
+            }
+
+            Poll::Pending
+        })
+    })
+    .await
+}
+
+// Give other tasks a chance to run.
+async fn yield_now() {
+    let mut yielded = false;
+
+    poll_fn(|cx| {
+        if yielded {
+            Poll::Ready(())
+        } else {
+            yielded = true;
+            cx.waker().wake_by_ref();
+            Poll::Pending
+        }
+    })
+    .await
+}
+
+impl<'e, 'h, E> EventQueue<'e, 'h, E> {
+    /// Wait until some event is due.
+    /// `now` returns the current tick count, `sleep_until` returns a future
+    /// resolving at the given tick, or never if there is no deadline.
+    pub async fn next_event<N, S, F>(&self, now: N, mut sleep_until: S)
+    where
+        N: Fn() -> TICKS,
+        S: FnMut(Option<TICKS>) -> F,
+        F: Future<Output = ()>,
+    {
+        loop {
+            // Events posted from now on are caught by `posted()`.
+            critical_section::with(|cs| POSTED.borrow(cs).set(false));
+
+            let ticks = now();
+            let deadline = self.next_deadline(ticks);
+            if deadline.is_some_and(|deadline| time_until(deadline, ticks) <= 0) {
+                return;
+            }
+
+            let mut sleep = pin!(sleep_until(deadline));
+            let mut posted = pin!(posted());
+
+            // Either the deadline passed or an event was posted, check again.
+            poll_fn(|cx| {
+                if sleep.as_mut().poll(cx).is_ready() || posted.as_mut().poll(cx).is_ready() {
+                    Poll::Ready(())
+                } else {
+                    Poll::Pending
+                }
+            })
+            .await;
+        }
+    }
+
+    /// Dispatch events forever, yielding to other tasks between dispatches.
+    /// Async counterpart of a `run_once` loop with sleep in between.
+    pub async fn run_async<N, S, F>(&mut self, now: N, mut sleep_until: S) -> !
+    where
+        N: Fn() -> TICKS,
+        S: FnMut(Option<TICKS>) -> F,
+        F: Future<Output = ()>,
+    {
+        loop {
+            self.next_event(&now, &mut sleep_until).await;
+            self.run_once(now());
+            yield_now().await;
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::Event;
+    use std::cell::Cell;
+    use std::sync::atomic::{AtomicBool, Ordering};
+    use std::sync::Arc;
+    use std::task::{Context, Wake};
+
+    struct Flag(AtomicBool);
+
+    impl Wake for Flag {
+        fn wake(self: Arc<Self>) {
+            self.0.store(true, Ordering::SeqCst);
+        }
+    }
+
+    #[test]
+    fn test_run_async() {
+        let done = Cell::new(0);
+        let clock = &Cell::new(0);
+
+        let handler = || done.set(done.get() + 1);
+        let event = Event::new(&handler);
+
+        let mut queue = EventQueue::new();
+        queue.bind(&event);
+
+        let flag = Arc::new(Flag(AtomicBool::new(false)));
+        let waker = Waker::from(flag.clone());
+        let mut cx = Context::from_waker(&waker);
+
+        let now = || clock.get();
+        let sleep_until = |deadline: Option<TICKS>| {
+            poll_fn(move |_| match deadline {
+                Some(deadline) if time_until(deadline, clock.get()) <= 0 => Poll::Ready(()),
+                _ => Poll::Pending,
+            })
+        };
+
+        let mut future = pin!(queue.run_async(now, sleep_until));
+
+        assert!(future.as_mut().poll(&mut cx).is_pending());
+        assert_eq!(done.get(), 0);
+
+        // Posting wakes the task, event is dispatched on the next poll.
+        event.call();
+        assert!(flag.0.swap(false, Ordering::SeqCst));
+
+        assert!(future.as_mut().poll(&mut cx).is_pending());
+        assert_eq!(done.get(), 1);
+
+        // Yielded after the dispatch.
+        assert!(flag.0.swap(false, Ordering::SeqCst));
+        assert!(future.as_mut().poll(&mut cx).is_pending());
+        assert_eq!(done.get(), 1);
+
+        event.call_on(100);
+        assert!(flag.0.swap(false, Ordering::SeqCst));
+        assert!(future.as_mut().poll(&mut cx).is_pending());
+        assert_eq!(done.get(), 1);
+
+        // Timer fired.
+        clock.set(100);
+        assert!(future.as_mut().poll(&mut cx).is_pending());
+        assert_eq!(done.get(), 2);
+    }
+}
@@ -2,78 +2,380 @@
 
 use core::cell::Cell;
 use core::cell::RefCell;
+use core::convert::Infallible;
 use core::fmt::{Debug, Formatter, Result};
 use core::ops::DerefMut;
 use critical_section::Mutex;
+use heapless::Deque;
 use intrusive_collections::{intrusive_adapter, LinkedList, LinkedListLink};
 
+#[cfg(feature = "async")]
+mod asynch;
+
 pub type TICKS = u32;
 
-#[derive(Debug)]
-pub struct EventQueue<'e, 'h> {
-    events: LinkedList<EventAdapter<'e, 'h>>,
+// Multiple queues
+//
+// A program may run several queues, e.g. the main one in thread mode and
+// a high priority one from an interrupt handler, so that time critical
+// events preempt slow ones. Each event is bound to one queue and its handler
+// only runs from that queue's run_once(). Locking rules:
+// - run_once(), bind() and unbind() of a queue are only called from the
+//   context that runs it, or before that context is started.
+// - Posting, cancelling and setting the period of an event, as well as
+//   Channel post() and take(), are interrupt-safe from any context.
+// - Handlers of a higher priority queue may run in the middle of any handler
+//   of a lower priority queue. Data shared by handlers of different queues must
+//   be accessed in a critical section, data used by handlers of one queue needs
+//   no locking.
+// - A queue run from an interrupt doesn't sleep, its wake hook pends the
+//   interrupt when one of its events is posted. Events posted for later time
+//   need the interrupt pended at the deadline too, e.g. by checking
+//   next_deadline() from the timer interrupt.
+
+/// What to do with an event after its handler returned an error.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum ErrorAction {
+    /// Keep the event state set by the dispatch.
+    Ignore,
+    /// Dispatch the event again after the given number of ticks.
+    Retry(TICKS),
+}
+
+pub struct EventQueue<'e, 'h, E = Infallible> {
+    // Created on first bind, LinkedList::new() isn't const.
+    events: Option<LinkedList<EventAdapter<'e, 'h, E>>>,
+    error_hook: Option<&'h dyn Fn(E) -> ErrorAction>,
+    wake_hook: Option<&'h dyn Fn()>,
+    #[cfg(feature = "stats")]
+    clock: Option<&'h dyn Fn() -> u32>,
 }
 
-intrusive_adapter!(EventAdapter<'e, 'h> = &'e Event<'h>: Event<'h> { link: LinkedListLink });
+intrusive_adapter!(EventAdapter<'e, 'h, E> = &'e Event<'h, E>: Event<'h, E> { link: LinkedListLink });
+
+impl<E> Debug for EventQueue<'_, '_, E> {
+    fn fmt(&self, f: &mut Formatter<'_>) -> Result {
+        f.debug_struct("EventQueue")
+            .field("events", &self.events)
+            .field("error_hook", &self.error_hook.is_some())
+            .field("wake_hook", &self.wake_hook.is_some())
+            .finish()
+    }
+}
 
-impl<'e, 'h> EventQueue<'e, 'h> {
-    pub fn new() -> Self {
+impl<'e, 'h, E> EventQueue<'e, 'h, E> {
+    pub const fn new() -> Self {
         EventQueue {
-            events: LinkedList::new(EventAdapter::new()),
+            events: None,
+            error_hook: None,
+            wake_hook: None,
+            #[cfg(feature = "stats")]
+            clock: None,
+        }
+    }
+
+    // Set clock used to measure handler run time for event statistics.
+    // Units are up to the caller, e.g. CPU cycles. The clock may wrap around.
+    #[cfg(feature = "stats")]
+    pub fn set_clock(&mut self, now: &'h dyn Fn() -> u32) {
+        self.clock = Some(now);
+    }
+
+    // Set function called when a fallible handler returns an error.
+    // Without the hook, handler errors panic.
+    pub fn set_error_hook(&mut self, hook: &'h dyn Fn(E) -> ErrorAction) {
+        self.error_hook = Some(hook);
+    }
+
+    // Set function called when an event bound to this queue is posted,
+    // e.g. to pend the interrupt that runs the queue. The hook is called
+    // in a critical section from the context that posted the event.
+    pub fn set_wake_hook(&mut self, hook: &'h dyn Fn()) {
+        self.wake_hook = Some(hook);
+
+        for event in self.events.iter().flatten() {
+            event.set_wake_hook(Some(hook));
         }
     }
 
-    pub fn bind(&mut self, event: &'e Event<'h>) {
-        self.events.push_back(event);
+    // Panics if the event is already bound to a queue.
+    pub fn bind(&mut self, event: &'e Event<'h, E>) {
+        self.events
+            .get_or_insert_with(|| LinkedList::new(EventAdapter::new()))
+            .push_back(event);
+        event.set_wake_hook(self.wake_hook);
+    }
+
+    // Remove the event from the queue and cancel its pending dispatch.
+    // Returns false if the event isn't bound to this queue.
+    // Dropping the queue unbinds all events, so they can be bound again.
+    pub fn unbind(&mut self, event: &'e Event<'h, E>) -> bool {
+        let Some(events) = self.events.as_mut() else {
+            return false;
+        };
+        let mut cursor = events.front_mut();
+
+        while let Some(bound) = cursor.get() {
+            if core::ptr::eq(bound, event) {
+                cursor.remove();
+                event.cancel();
+                event.set_wake_hook(None);
+                return true;
+            }
+            cursor.move_next();
+        }
+
+        false
     }
 
     // Check all registered events once and execute all pending handlers.
-    pub fn run_once(&self, ticks: TICKS) {
-        let mut cursor = self.events.front();
+    // Due events are dispatched highest priority first, then soonest first.
+    // Events posted by handlers for immediate dispatch run in the same pass,
+    // but each event runs at most once.
+    pub fn run_once(&mut self, ticks: TICKS) {
+        let error_hook = self.error_hook;
+        #[cfg(feature = "stats")]
+        let clock = self.clock;
+
+        let Some(events) = self.events.as_mut() else {
+            return;
+        };
+        let mut done = LinkedList::new(EventAdapter::new());
 
         loop {
-            match cursor.get() {
-                None => break,
-                Some(event) => {
-                    let dispatch = critical_section::with(|cs| {
-                        let state = *event.state.borrow_ref(cs);
-                        let period = event.period.borrow(cs).get();
-
-                        let (dispatch, event_time) = match state {
-                            EventState::Done => (false, ticks),
-                            EventState::DispatchNow => (true, ticks),
-                            EventState::DispatchAt(dispatch_time) => {
-                                (dispatch_time <= ticks, dispatch_time)
-                            }
-                        };
-
-                        if dispatch {
-                            match period {
-                                None => event.state.replace(cs, EventState::Done),
-                                Some(duration) => event
-                                    .state
-                                    .replace(cs, EventState::DispatchAt(event_time + duration)),
-                            };
-                        }
+            let mut due = take_due_events(events, ticks);
+            if due.is_empty() {
+                break;
+            }
+
+            while let Some(event) = due.pop_front() {
+                if event.take_due(ticks) {
+                    #[cfg(feature = "stats")]
+                    let start = clock.map(|now| now());
+
+                    let result = event.run_handler();
 
-                        dispatch
-                    });
+                    #[cfg(feature = "stats")]
+                    event.record_dispatch(
+                        start
+                            .zip(clock)
+                            .map(|(start, now)| now().wrapping_sub(start)),
+                    );
 
-                    if dispatch {
-                        match event.handler.borrow_mut().deref_mut() {
-                            Handler::Fn(h) => h(),
-                            Handler::FnMut(h) => h(),
+                    if let Err(error) = result {
+                        let hook = error_hook.expect("event handler failed");
+
+                        if let ErrorAction::Retry(delay) = hook(error) {
+                            event.call_on(ticks.wrapping_add(delay));
                         }
                     }
+                }
+                done.push_back(event);
+            }
+        }
 
-                    cursor.move_next();
+        while let Some(event) = done.pop_front() {
+            events.push_back(event);
+        }
+    }
+
+    // Get the earliest time any of the registered events is due.
+    // Returns `ticks` if some event is waiting for immediate dispatch
+    // and None if nothing is scheduled.
+    pub fn next_deadline(&self, ticks: TICKS) -> Option<TICKS> {
+        self.events
+            .iter()
+            .flatten()
+            .filter_map(|event| event.deadline(ticks))
+            .min_by_key(|&deadline| time_until(deadline, ticks))
+    }
+
+    // Move all events of the other queue to this one.
+    fn append(&mut self, other: &mut EventQueue<'e, 'h, E>) {
+        while let Some(event) = other.events.as_mut().and_then(|events| events.pop_front()) {
+            self.bind(event);
+        }
+    }
+}
+
+// Move the events that are due to a list in dispatch order, all in one critical section.
+// Highest priority goes first, then the soonest deadline. Events due at the same time
+// keep their relative order.
+fn take_due_events<'e, 'h, E>(
+    events: &mut LinkedList<EventAdapter<'e, 'h, E>>,
+    ticks: TICKS,
+) -> LinkedList<EventAdapter<'e, 'h, E>> {
+    let mut due = LinkedList::new(EventAdapter::new());
+
+    critical_section::with(|cs| {
+        let mut cursor = events.front_mut();
+
+        while let Some(event) = cursor.get() {
+            let Some(deadline) = event
+                .deadline_in(cs, ticks)
+                .filter(|&deadline| time_until(deadline, ticks) <= 0)
+            else {
+                cursor.move_next();
+                continue;
+            };
+            let event = cursor.remove().unwrap();
+
+            let mut due_cursor = due.back_mut();
+            while let Some(other) = due_cursor.get() {
+                let other_deadline = other.deadline_in(cs, ticks).unwrap_or(ticks);
+                let is_later = other.priority < event.priority
+                    || (other.priority == event.priority
+                        && time_until(other_deadline, ticks) > time_until(deadline, ticks));
+                if !is_later {
+                    break;
                 }
+                due_cursor.move_prev();
             }
+
+            due_cursor.insert_after(event);
+        }
+    });
+
+    due
+}
+
+fn time_until(deadline: TICKS, ticks: TICKS) -> i32 {
+    deadline.wrapping_sub(ticks) as i32
+}
+
+impl<'e, 'h, E> Drop for EventQueue<'e, 'h, E> {
+    // Events keep their state, but posting them no longer wakes this queue.
+    fn drop(&mut self) {
+        for event in self.events.iter().flatten() {
+            event.set_wake_hook(None);
+        }
+    }
+}
+
+impl<'e, 'h, E> Default for EventQueue<'e, 'h, E> {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+/// Event queue that can be placed in a `static`.
+/// Events may be bound from any context, including interrupt handlers and
+/// handlers of this queue. They join the queue on its next run.
+/// Other methods are `unsafe`, see the safety rule below.
+///
+/// # Safety
+///
+/// `set_clock`, `set_error_hook`, `set_wake_hook`, `unbind`, `run_once` and
+/// `next_deadline` must all be called from the same single context, the one
+/// that runs the queue. They must not be called from an interrupt handler that
+/// can preempt that context.
+pub struct StaticEventQueue<E: 'static = Infallible> {
+    // Only used from the context that runs the queue, no locking necessary.
+    queue: RefCell<EventQueue<'static, 'static, E>>,
+    // Events bound since the last run. Protected.
+    pending: Mutex<RefCell<EventQueue<'static, 'static, E>>>,
+    // Wake hook of the queue, for pending events. Protected.
+    wake_hook: Mutex<Cell<Option<&'static dyn Fn()>>>,
+}
+
+// The queue itself is only used from one context, as required by the unsafe
+// methods that access it. The rest is protected.
+unsafe impl<E> Sync for StaticEventQueue<E> {}
+
+impl<E> Debug for StaticEventQueue<E> {
+    fn fmt(&self, f: &mut Formatter<'_>) -> Result {
+        f.debug_struct("StaticEventQueue")
+            .field("queue", &self.queue)
+            .finish()
+    }
+}
+
+impl<E> StaticEventQueue<E> {
+    pub const fn new() -> Self {
+        StaticEventQueue {
+            queue: RefCell::new(EventQueue::new()),
+            pending: Mutex::new(RefCell::new(EventQueue::new())),
+            wake_hook: Mutex::new(Cell::new(None)),
         }
     }
+
+    /// # Safety
+    ///
+    /// Only called from the context that runs the queue.
+    #[cfg(feature = "stats")]
+    pub unsafe fn set_clock(&self, now: &'static dyn Fn() -> u32) {
+        self.queue.borrow_mut().set_clock(now);
+    }
+
+    /// # Safety
+    ///
+    /// Only called from the context that runs the queue.
+    pub unsafe fn set_error_hook(&self, hook: &'static dyn Fn(E) -> ErrorAction) {
+        self.queue.borrow_mut().set_error_hook(hook);
+    }
+
+    /// # Safety
+    ///
+    /// Only called from the context that runs the queue.
+    pub unsafe fn set_wake_hook(&self, hook: &'static dyn Fn()) {
+        self.queue.borrow_mut().set_wake_hook(hook);
+
+        critical_section::with(|cs| {
+            self.wake_hook.borrow(cs).set(Some(hook));
+            self.pending.borrow_ref_mut(cs).set_wake_hook(hook);
+        });
+    }
+
+    /// Add the event to the queue on its next run. Wakes the queue.
+    /// Panics if the event is already bound to a queue.
+    /// This function is interrupt-safe.
+    pub fn bind(&self, event: &'static Event<'static, E>) {
+        critical_section::with(|cs| {
+            self.pending.borrow_ref_mut(cs).bind(event);
+
+            if let Some(hook) = self.wake_hook.borrow(cs).get() {
+                hook();
+            }
+        });
+    }
+
+    /// Not called from handlers of this queue.
+    ///
+    /// # Safety
+    ///
+    /// Only called from the context that runs the queue.
+    pub unsafe fn unbind(&self, event: &'static Event<'static, E>) -> bool {
+        critical_section::with(|cs| self.pending.borrow_ref_mut(cs).unbind(event))
+            || self.queue.borrow_mut().unbind(event)
+    }
+
+    /// # Safety
+    ///
+    /// Only called from the context that runs the queue, never from its
+    /// handlers.
+    pub unsafe fn run_once(&self, ticks: TICKS) {
+        let mut queue = self.queue.borrow_mut();
+
+        critical_section::with(|cs| queue.append(&mut self.pending.borrow_ref_mut(cs)));
+        queue.run_once(ticks);
+    }
+
+    /// Not called from handlers of this queue.
+    ///
+    /// # Safety
+    ///
+    /// Only called from the context that runs the queue.
+    pub unsafe fn next_deadline(&self, ticks: TICKS) -> Option<TICKS> {
+        let pending = critical_section::with(|cs| self.pending.borrow_ref(cs).next_deadline(ticks));
+
+        pending
+            .into_iter()
+            .chain(self.queue.borrow().next_deadline(ticks))
+            .min_by_key(|&deadline| time_until(deadline, ticks))
+    }
 }
 
-impl<'e, 'h> Default for EventQueue<'e, 'h> {
+impl<E> Default for StaticEventQueue<E> {
     fn default() -> Self {
         Self::new()
     }
@@ -86,32 +388,108 @@ enum EventState {
     DispatchAt(TICKS),
 }
 
-enum Handler<'h> {
+/// How a periodic event is rescheduled when its dispatch was late.
+/// In both cases dispatch times stay on the phase of the first dispatch.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Overrun {
+    /// Dispatch once for every missed period until the event catches up.
+    CatchUp,
+    /// Drop missed periods and wait for the next one.
+    Skip,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+struct Period {
+    duration: TICKS,
+    overrun: Overrun,
+}
+
+impl Period {
+    // Get the next dispatch time after the event planned for `event_time` ran at `ticks`.
+    fn next_time(&self, event_time: TICKS, ticks: TICKS) -> TICKS {
+        // Zero period is due on every run. Following the current time keeps it due,
+        // a fixed deadline stops being due once the clock is half a wrap past it.
+        if self.duration == 0 {
+            return ticks;
+        }
+
+        let next_time = event_time.wrapping_add(self.duration);
+
+        match self.overrun {
+            Overrun::CatchUp => next_time,
+            Overrun::Skip if time_until(next_time, ticks) > 0 => next_time,
+            Overrun::Skip => {
+                let missed = ticks.wrapping_sub(event_time) / self.duration;
+                event_time.wrapping_add((missed + 1).wrapping_mul(self.duration))
+            }
+        }
+    }
+}
+
+/// Run-time statistics of an event.
+#[cfg(feature = "stats")]
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct EventStats {
+    /// Number of handler runs.
+    pub dispatches: u32,
+    /// Sum of handler run times, in units of the queue clock.
+    /// Zero if the queue has no clock.
+    pub total_duration: u32,
+    /// Longest handler run time.
+    pub max_duration: u32,
+    /// Number of periodic dispatches late by a full period or more.
+    pub missed_deadlines: u32,
+}
+
+#[cfg(feature = "stats")]
+impl EventStats {
+    pub fn avg_duration(&self) -> u32 {
+        self.total_duration
+            .checked_div(self.dispatches)
+            .unwrap_or(0)
+    }
+}
+
+enum Handler<'h, E> {
     Fn(&'h dyn Fn()),
     FnMut(&'h mut dyn FnMut()),
+    Fallible(&'h dyn Fn() -> core::result::Result<(), E>),
+    FallibleMut(&'h mut dyn FnMut() -> core::result::Result<(), E>),
 }
 
-impl<'h> Debug for Handler<'h> {
+impl<'h, E> Debug for Handler<'h, E> {
     fn fmt(&self, f: &mut Formatter<'_>) -> Result {
         match self {
             Handler::Fn(_) => f.write_str("Handler::Fn(_)"),
             Handler::FnMut(_) => f.write_str("Handler::FnMut(_)"),
+            Handler::Fallible(_) => f.write_str("Handler::Fallible(_)"),
+            Handler::FallibleMut(_) => f.write_str("Handler::FallibleMut(_)"),
         }
     }
 }
 
-pub struct Event<'h> {
-    // Only changes in EventQueue::bind(), no locking necessary.
+pub struct Event<'h, E = Infallible> {
+    // Only changes in EventQueue::bind(), EventQueue::unbind() and EventQueue::run_once(),
+    // no locking necessary.
     link: LinkedListLink,
     // Protected.
     state: Mutex<RefCell<EventState>>,
     // Protected.
-    period: Mutex<Cell<Option<TICKS>>>,
+    period: Mutex<Cell<Option<Period>>>,
     // Never changes, no locking necessary.
-    handler: RefCell<Handler<'h>>,
+    handler: RefCell<Handler<'h, E>>,
+    // Never changes, no locking necessary.
+    priority: u8,
+    // Wake hook of the queue the event is bound to. Protected.
+    wake_hook: Mutex<Cell<Option<&'h dyn Fn()>>>,
+    // Changes every time the event is posted, cancelled or dispatched. Protected.
+    generation: Mutex<Cell<u32>>,
+    // Protected.
+    #[cfg(feature = "stats")]
+    stats: Mutex<Cell<EventStats>>,
 }
 
-impl Debug for Event<'_> {
+impl<E> Debug for Event<'_, E> {
     fn fmt(&self, f: &mut Formatter<'_>) -> Result {
         f.debug_struct("Event")
             .field(
@@ -122,64 +500,346 @@ impl Debug for Event<'_> {
                 "period",
                 &critical_section::with(|cs| self.period.borrow(cs).get()),
             )
+            .field("priority", &self.priority)
             .finish()
     }
 }
 
-unsafe impl<'h> Sync for Event<'h> {}
+unsafe impl<'h, E> Sync for Event<'h, E> {}
 
 impl<'h> Event<'h> {
     pub const fn new(handler: &'h dyn Fn()) -> Self {
-        Self {
-            link: LinkedListLink::new(),
-            state: Mutex::new(RefCell::new(EventState::Done)),
-            period: Mutex::new(Cell::new(None)),
-            handler: RefCell::new(Handler::Fn(handler)),
-        }
+        Self::with_handler(Handler::Fn(handler))
     }
 
     pub fn new_mut(handler: &'h mut dyn FnMut()) -> Self {
+        Self::with_handler(Handler::FnMut(handler))
+    }
+}
+
+impl<'h, E> Event<'h, E> {
+    // Get the time the event is due, or None if it isn't scheduled.
+    fn deadline(&self, ticks: TICKS) -> Option<TICKS> {
+        critical_section::with(|cs| self.deadline_in(cs, ticks))
+    }
+
+    fn deadline_in(&self, cs: critical_section::CriticalSection, ticks: TICKS) -> Option<TICKS> {
+        match *self.state.borrow_ref(cs) {
+            EventState::Done => None,
+            EventState::DispatchNow => Some(ticks),
+            EventState::DispatchAt(dispatch_time) => Some(dispatch_time),
+        }
+    }
+
+    // Check if the event is due and reschedule periodic events.
+    fn take_due(&self, ticks: TICKS) -> bool {
+        critical_section::with(|cs| {
+            let state = *self.state.borrow_ref(cs);
+            let period = self.period.borrow(cs).get();
+
+            let (dispatch, event_time) = match state {
+                EventState::Done => (false, ticks),
+                EventState::DispatchNow => (true, ticks),
+                EventState::DispatchAt(dispatch_time) => {
+                    (time_until(dispatch_time, ticks) <= 0, dispatch_time)
+                }
+            };
+
+            if dispatch {
+                self.next_generation(cs);
+
+                match period {
+                    None => self.state.replace(cs, EventState::Done),
+                    Some(period) => {
+                        #[cfg(feature = "stats")]
+                        if period.duration > 0 && ticks.wrapping_sub(event_time) >= period.duration
+                        {
+                            self.update_stats(cs, |stats| stats.missed_deadlines += 1);
+                        }
+
+                        self.state.replace(
+                            cs,
+                            EventState::DispatchAt(period.next_time(event_time, ticks)),
+                        )
+                    }
+                };
+            }
+
+            dispatch
+        })
+    }
+
+    fn set_wake_hook(&self, hook: Option<&'h dyn Fn()>) {
+        critical_section::with(|cs| self.wake_hook.borrow(cs).set(hook));
+    }
+
+    // Called with the event state updated.
+    fn notify_posted(&self, cs: critical_section::CriticalSection) {
+        if let Some(hook) = self.wake_hook.borrow(cs).get() {
+            hook();
+        }
+
+        #[cfg(feature = "async")]
+        asynch::notify_posted(cs);
+    }
+
+    fn run_handler(&self) -> core::result::Result<(), E> {
+        match self.handler.borrow_mut().deref_mut() {
+            Handler::Fn(h) => h(),
+            Handler::FnMut(h) => h(),
+            Handler::Fallible(h) => h()?,
+            Handler::FallibleMut(h) => h()?,
+        }
+
+        Ok(())
+    }
+
+    #[cfg(feature = "stats")]
+    fn update_stats(&self, cs: critical_section::CriticalSection, f: impl FnOnce(&mut EventStats)) {
+        let cell = self.stats.borrow(cs);
+        let mut stats = cell.get();
+        f(&mut stats);
+        cell.set(stats);
+    }
+
+    #[cfg(feature = "stats")]
+    fn record_dispatch(&self, duration: Option<u32>) {
+        critical_section::with(|cs| {
+            self.update_stats(cs, |stats| {
+                stats.dispatches += 1;
+                if let Some(duration) = duration {
+                    stats.total_duration = stats.total_duration.wrapping_add(duration);
+                    stats.max_duration = stats.max_duration.max(duration);
+                }
+            })
+        });
+    }
+
+    const fn with_handler(handler: Handler<'h, E>) -> Self {
         Self {
             link: LinkedListLink::new(),
             state: Mutex::new(RefCell::new(EventState::Done)),
             period: Mutex::new(Cell::new(None)),
-            handler: RefCell::new(Handler::FnMut(handler)),
+            handler: RefCell::new(handler),
+            priority: 0,
+            wake_hook: Mutex::new(Cell::new(None)),
+            generation: Mutex::new(Cell::new(0)),
+            #[cfg(feature = "stats")]
+            stats: Mutex::new(Cell::new(EventStats {
+                dispatches: 0,
+                total_duration: 0,
+                max_duration: 0,
+                missed_deadlines: 0,
+            })),
         }
     }
 
+    /// Get a snapshot of the event statistics.
+    /// This function is interrupt-safe.
+    #[cfg(feature = "stats")]
+    pub fn stats(&self) -> EventStats {
+        critical_section::with(|cs| self.stats.borrow(cs).get())
+    }
+
+    /// Clear the event statistics.
+    /// This function is interrupt-safe.
+    #[cfg(feature = "stats")]
+    pub fn reset_stats(&self) {
+        critical_section::with(|cs| self.stats.borrow(cs).set(EventStats::default()));
+    }
+
+    /// Create an event with a handler that can fail.
+    /// Errors are passed to the error hook of the queue.
+    pub const fn new_fallible(handler: &'h dyn Fn() -> core::result::Result<(), E>) -> Self {
+        Self::with_handler(Handler::Fallible(handler))
+    }
+
+    pub fn new_fallible_mut(handler: &'h mut dyn FnMut() -> core::result::Result<(), E>) -> Self {
+        Self::with_handler(Handler::FallibleMut(handler))
+    }
+
+    /// Set dispatch priority, 0 by default.
+    /// Due events with higher priority are dispatched before the ones with lower priority,
+    /// regardless of how long the latter are waiting.
+    pub const fn with_priority(mut self, priority: u8) -> Self {
+        self.priority = priority;
+        self
+    }
+
     /// Cancel dispatch of the event.
     /// This function is interrupt-safe.
     pub fn cancel(&self) {
         critical_section::with(|cs| {
             self.state.replace(cs, EventState::Done);
+            self.next_generation(cs);
         });
     }
 
     /// Post event into message queue for immediate dispatch.
     /// This function is interrupt-safe.
-    pub fn call(&self) {
-        critical_section::with(|cs| {
-            self.state.replace(cs, EventState::DispatchNow);
-        });
+    pub fn call(&self) -> TimerHandle<'_, 'h, E> {
+        critical_section::with(|cs| self.post(cs, EventState::DispatchNow))
     }
 
     /// Post an event into message queue with a delay before dispatching the event.
     /// This function is interrupt-safe.
-    pub fn call_on(&self, time: TICKS) {
-        critical_section::with(|cs| {
-            self.state.replace(cs, EventState::DispatchAt(time));
-        });
+    pub fn call_on(&self, time: TICKS) -> TimerHandle<'_, 'h, E> {
+        critical_section::with(|cs| self.post(cs, EventState::DispatchAt(time)))
+    }
+
+    fn post(
+        &self,
+        cs: critical_section::CriticalSection,
+        state: EventState,
+    ) -> TimerHandle<'_, 'h, E> {
+        self.state.replace(cs, state);
+        let generation = self.next_generation(cs);
+        self.notify_posted(cs);
+
+        TimerHandle {
+            event: self,
+            generation,
+        }
+    }
+
+    // Invalidate handles of the previous post.
+    fn next_generation(&self, cs: critical_section::CriticalSection) -> u32 {
+        let cell = self.generation.borrow(cs);
+        let generation = cell.get().wrapping_add(1);
+        cell.set(generation);
+
+        generation
     }
 
     /// Set period for repeatedly dispatching an event.
+    /// Late dispatches are caught up.
+    /// Zero period dispatches the event on every run of the queue.
     /// This function is interrupt-safe.
     pub fn period(&self, period: TICKS) {
+        self.period_with_overrun(period, Overrun::CatchUp);
+    }
+
+    /// Set period for repeatedly dispatching an event and the way late dispatches are handled.
+    /// Zero period dispatches the event on every run of the queue.
+    /// This function is interrupt-safe.
+    pub fn period_with_overrun(&self, period: TICKS, overrun: Overrun) {
         critical_section::with(|cs| {
-            self.period.borrow(cs).set(Some(period));
+            self.period.borrow(cs).set(Some(Period {
+                duration: period,
+                overrun,
+            }));
         });
     }
 }
 
+/// Handle to one posted dispatch of an event, returned by Event::call() and Event::call_on().
+/// The handle becomes stale when the event is dispatched, cancelled or posted again,
+/// after that it doesn't affect the event anymore. This allows to cancel a timeout
+/// without checking whether it was restarted by someone else meanwhile.
+pub struct TimerHandle<'e, 'h, E = Infallible> {
+    event: &'e Event<'h, E>,
+    generation: u32,
+}
+
+impl<E> Clone for TimerHandle<'_, '_, E> {
+    fn clone(&self) -> Self {
+        *self
+    }
+}
+
+impl<E> Copy for TimerHandle<'_, '_, E> {}
+
+impl<E> Debug for TimerHandle<'_, '_, E> {
+    fn fmt(&self, f: &mut Formatter<'_>) -> Result {
+        f.debug_struct("TimerHandle")
+            .field("event", self.event)
+            .field("generation", &self.generation)
+            .finish()
+    }
+}
+
+impl<'e, 'h, E> TimerHandle<'e, 'h, E> {
+    /// Check if the dispatch is still waiting.
+    /// This function is interrupt-safe.
+    pub fn is_pending(&self) -> bool {
+        critical_section::with(|cs| self.is_current(cs))
+    }
+
+    /// Cancel the dispatch. Returns false if the handle is stale.
+    /// This function is interrupt-safe.
+    pub fn cancel(&self) -> bool {
+        critical_section::with(|cs| {
+            let current = self.is_current(cs);
+            if current {
+                self.event.state.replace(cs, EventState::Done);
+                self.event.next_generation(cs);
+            }
+
+            current
+        })
+    }
+
+    /// Move the dispatch to another time. Returns the handle of the new dispatch,
+    /// or None if this handle is stale.
+    /// This function is interrupt-safe.
+    pub fn reschedule(&self, time: TICKS) -> Option<TimerHandle<'e, 'h, E>> {
+        critical_section::with(|cs| {
+            self.is_current(cs)
+                .then(|| self.event.post(cs, EventState::DispatchAt(time)))
+        })
+    }
+
+    fn is_current(&self, cs: critical_section::CriticalSection) -> bool {
+        self.event.generation.borrow(cs).get() == self.generation
+    }
+}
+
+/// Bounded queue of messages for an event handler.
+/// Posting a message schedules the event for immediate dispatch,
+/// the handler then takes pending messages out of the channel.
+pub struct Channel<'e, 'h, T, const N: usize, E = Infallible> {
+    event: &'e Event<'h, E>,
+    // Protected.
+    messages: Mutex<RefCell<Deque<T, N>>>,
+}
+
+impl<T, const N: usize, E> Debug for Channel<'_, '_, T, N, E> {
+    fn fmt(&self, f: &mut Formatter<'_>) -> Result {
+        f.debug_struct("Channel")
+            .field("event", self.event)
+            .field(
+                "len",
+                &critical_section::with(|cs| self.messages.borrow_ref(cs).len()),
+            )
+            .finish()
+    }
+}
+
+impl<'e, 'h, T, const N: usize, E> Channel<'e, 'h, T, N, E> {
+    pub const fn new(event: &'e Event<'h, E>) -> Self {
+        Self {
+            event,
+            messages: Mutex::new(RefCell::new(Deque::new())),
+        }
+    }
+
+    /// Add a message to the channel and post the event for immediate dispatch.
+    /// Returns the message back if the channel is full.
+    /// This function is interrupt-safe.
+    pub fn post(&self, message: T) -> core::result::Result<(), T> {
+        critical_section::with(|cs| self.messages.borrow_ref_mut(cs).push_back(message))?;
+        self.event.call();
+
+        Ok(())
+    }
+
+    /// Take the oldest message out of the channel.
+    /// This function is interrupt-safe.
+    pub fn take(&self) -> Option<T> {
+        critical_section::with(|cs| self.messages.borrow_ref_mut(cs).pop_front())
+    }
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
@@ -306,32 +966,858 @@ mod tests {
         queue.run_once(210);
         assert_eq!(*done.borrow(), 3);
     }
-}
 
-#[cfg(test)]
-mod static_tests {
-    use super::*;
+    #[test]
+    fn test_delayed_post_wraparound() {
+        let done = Cell::new(false);
 
-    static DONE: Mutex<Cell<bool>> = Mutex::new(Cell::new(false));
+        let handler = || {
+            done.set(true);
+        };
 
-    fn handler() {
-        critical_section::with(|cs| {
-            DONE.borrow(cs).set(true);
-        });
-    }
+        let event = Event::new(&handler);
+        let mut queue = EventQueue::new();
 
-    static EVENT: Event = Event::new(&handler);
+        queue.bind(&event);
+        // Deadline is 61 ticks ahead, after the counter wraps.
+        event.call_on(50);
 
-    #[test]
-    fn test_post_static_event() {
-        let mut queue = EventQueue::new();
+        queue.run_once(TICKS::MAX - 10);
+        assert!(!done.get());
 
-        queue.bind(&EVENT);
-        EVENT.call();
-        queue.run_once(0);
+        queue.run_once(10);
+        assert!(!done.get());
 
-        let done = critical_section::with(|cs| DONE.borrow(cs).get());
+        queue.run_once(49);
+        assert!(!done.get());
 
-        assert!(done);
+        queue.run_once(50);
+        assert!(done.get());
+    }
+
+    #[test]
+    fn test_periodic_event_catch_up() {
+        let done = RefCell::new(0);
+
+        let handler = || {
+            done.replace_with(|n| *n + 1);
+        };
+
+        let event = Event::new(&handler);
+        event.period_with_overrun(100, Overrun::CatchUp);
+
+        let mut queue = EventQueue::new();
+        queue.bind(&event);
+
+        event.call_on(100);
+
+        // Three periods late, every missed dispatch runs.
+        for expected in 1..=3 {
+            queue.run_once(350);
+            assert_eq!(*done.borrow(), expected);
+        }
+        assert_eq!(queue.next_deadline(350), Some(400));
+
+        queue.run_once(399);
+        assert_eq!(*done.borrow(), 3);
+
+        queue.run_once(400);
+        assert_eq!(*done.borrow(), 4);
+    }
+
+    #[test]
+    fn test_periodic_event_skip() {
+        let done = RefCell::new(0);
+
+        let handler = || {
+            done.replace_with(|n| *n + 1);
+        };
+
+        let event = Event::new(&handler);
+        event.period_with_overrun(100, Overrun::Skip);
+
+        let mut queue = EventQueue::new();
+        queue.bind(&event);
+
+        event.call_on(100);
+
+        // Three periods late, missed dispatches are dropped.
+        queue.run_once(350);
+        assert_eq!(*done.borrow(), 1);
+        assert_eq!(queue.next_deadline(350), Some(400));
+
+        queue.run_once(360);
+        assert_eq!(*done.borrow(), 1);
+
+        queue.run_once(400);
+        assert_eq!(*done.borrow(), 2);
+    }
+
+    #[test]
+    fn test_periodic_event_zero_period() {
+        for overrun in [Overrun::CatchUp, Overrun::Skip] {
+            let done = RefCell::new(0);
+
+            let handler = || {
+                done.replace_with(|n| *n + 1);
+            };
+
+            let event = Event::new(&handler);
+            event.period_with_overrun(0, overrun);
+
+            let mut queue = EventQueue::new();
+            queue.bind(&event);
+
+            event.call_on(100);
+
+            queue.run_once(50);
+            assert_eq!(*done.borrow(), 0);
+
+            // Due on every run, once per run.
+            queue.run_once(100);
+            assert_eq!(*done.borrow(), 1);
+            assert_eq!(queue.next_deadline(100), Some(100));
+
+            queue.run_once(100);
+            assert_eq!(*done.borrow(), 2);
+
+            queue.run_once(250);
+            assert_eq!(*done.borrow(), 3);
+
+            // Still due half a counter wrap after the first dispatch and later.
+            queue.run_once(100 + 0x8000_0000);
+            assert_eq!(*done.borrow(), 4);
+
+            queue.run_once(101 + 0x8000_0000);
+            assert_eq!(*done.borrow(), 5);
+        }
+    }
+
+    #[test]
+    fn test_periodic_event_stable_phase() {
+        for overrun in [Overrun::CatchUp, Overrun::Skip] {
+            let done = RefCell::new(0);
+
+            let handler = || {
+                done.replace_with(|n| *n + 1);
+            };
+
+            let event = Event::new(&handler);
+            event.period_with_overrun(100, overrun);
+
+            let mut queue = EventQueue::new();
+            queue.bind(&event);
+
+            event.call_on(30);
+
+            // Dispatch each period with varying lateness, phase doesn't drift.
+            for period in 0..1000u32 {
+                let deadline = 30 + period * 100;
+                assert_eq!(queue.next_deadline(deadline), Some(deadline));
+
+                queue.run_once(deadline + period % 7 * 10);
+                assert_eq!(*done.borrow(), period + 1);
+            }
+        }
+    }
+
+    #[test]
+    fn test_periodic_event_skip_wraparound() {
+        let done = RefCell::new(0);
+
+        let handler = || {
+            done.replace_with(|n| *n + 1);
+        };
+
+        let event = Event::new(&handler);
+        event.period_with_overrun(100, Overrun::Skip);
+
+        let mut queue = EventQueue::new();
+        queue.bind(&event);
+
+        event.call_on(TICKS::MAX - 149);
+
+        // Late by two and a half periods, next dispatch is at 150 after the counter wraps.
+        queue.run_once(100);
+        assert_eq!(*done.borrow(), 1);
+        assert_eq!(queue.next_deadline(100), Some(150));
+    }
+
+    #[test]
+    fn test_periodic_event_wraparound() {
+        let done = RefCell::new(0);
+
+        let handler = || {
+            done.replace_with(|n| *n + 1);
+        };
+
+        let event = Event::new(&handler);
+        event.period(100);
+
+        let mut queue = EventQueue::new();
+        queue.bind(&event);
+
+        event.call_on(TICKS::MAX - 49);
+
+        queue.run_once(TICKS::MAX - 49);
+        assert_eq!(*done.borrow(), 1);
+
+        // Next dispatch is at 50 after the counter wraps.
+        queue.run_once(TICKS::MAX);
+        assert_eq!(*done.borrow(), 1);
+
+        queue.run_once(49);
+        assert_eq!(*done.borrow(), 1);
+
+        queue.run_once(50);
+        assert_eq!(*done.borrow(), 2);
+    }
+
+    #[test]
+    fn test_dispatch_order_wraparound() {
+        let order = RefCell::new(std::vec::Vec::new());
+
+        let first_handler = || order.borrow_mut().push(1);
+        let second_handler = || order.borrow_mut().push(2);
+
+        let first = Event::new(&first_handler);
+        let second = Event::new(&second_handler);
+
+        let mut queue = EventQueue::new();
+        queue.bind(&second);
+        queue.bind(&first);
+
+        first.call_on(TICKS::MAX - 5);
+        second.call_on(5);
+
+        assert_eq!(queue.next_deadline(TICKS::MAX - 10), Some(TICKS::MAX - 5));
+
+        queue.run_once(10);
+        assert_eq!(*order.borrow(), [1, 2]);
+    }
+
+    #[test]
+    fn test_not_due_before_wraparound() {
+        let done = Cell::new(false);
+        let handler = || done.set(true);
+
+        let event = Event::new(&handler);
+        let mut queue = EventQueue::new();
+        queue.bind(&event);
+
+        // Numerically smaller, but after the counter wraps.
+        event.call_on(5);
+
+        queue.run_once(TICKS::MAX - 10);
+        assert!(!done.get());
+        assert_eq!(queue.next_deadline(TICKS::MAX - 10), Some(5));
+
+        queue.run_once(TICKS::MAX);
+        assert!(!done.get());
+
+        queue.run_once(5);
+        assert!(done.get());
+    }
+
+    #[test]
+    fn test_dispatch_priority_wraparound() {
+        let order = RefCell::new(std::vec::Vec::new());
+
+        let low_handler = || order.borrow_mut().push(1);
+        let high_handler = || order.borrow_mut().push(2);
+
+        let low = Event::new(&low_handler);
+        let high = Event::new(&high_handler).with_priority(1);
+
+        let mut queue = EventQueue::new();
+        queue.bind(&low);
+        queue.bind(&high);
+
+        low.call_on(TICKS::MAX - 5);
+        high.call_on(5);
+
+        queue.run_once(10);
+        assert_eq!(*order.borrow(), [2, 1]);
+    }
+
+    #[test]
+    fn test_channel() {
+        let received = RefCell::new(std::vec::Vec::new());
+        let channel_ref = Cell::new(None);
+
+        let handler = || {
+            let channel: &Channel<u8, 2> = channel_ref.get().unwrap();
+            while let Some(message) = channel.take() {
+                received.borrow_mut().push(message);
+            }
+        };
+
+        let event = Event::new(&handler);
+        let channel = Channel::new(&event);
+        channel_ref.set(Some(&channel));
+
+        let mut queue = EventQueue::new();
+        queue.bind(&event);
+
+        assert_eq!(channel.post(1), Ok(()));
+        assert_eq!(channel.post(2), Ok(()));
+        assert_eq!(channel.post(3), Err(3));
+
+        queue.run_once(0);
+        assert_eq!(*received.borrow(), [1, 2]);
+
+        // Nothing posted, handler doesn't run.
+        queue.run_once(10);
+        assert_eq!(*received.borrow(), [1, 2]);
+
+        assert_eq!(channel.post(4), Ok(()));
+        queue.run_once(20);
+        assert_eq!(*received.borrow(), [1, 2, 4]);
+    }
+
+    #[test]
+    fn test_dispatch_order() {
+        let order = RefCell::new(std::vec::Vec::new());
+
+        let first_handler = || order.borrow_mut().push(1);
+        let second_handler = || order.borrow_mut().push(2);
+        let third_handler = || order.borrow_mut().push(3);
+
+        let first = Event::new(&first_handler);
+        let second = Event::new(&second_handler);
+        let third = Event::new(&third_handler);
+
+        let mut queue = EventQueue::new();
+        queue.bind(&third);
+        queue.bind(&second);
+        queue.bind(&first);
+
+        third.call();
+        second.call_on(50);
+        first.call_on(20);
+
+        queue.run_once(100);
+        assert_eq!(*order.borrow(), [1, 2, 3]);
+    }
+
+    #[test]
+    fn test_dispatch_posted_by_handler() {
+        let order = RefCell::new(std::vec::Vec::new());
+
+        let second_handler = || order.borrow_mut().push(2);
+        let second = Event::new(&second_handler);
+
+        let first_handler = || {
+            order.borrow_mut().push(1);
+            second.call();
+        };
+        let first = Event::new(&first_handler);
+
+        // Reposts itself, but runs once per pass.
+        let repost_handler = || order.borrow_mut().push(3);
+        let repost = Event::new(&repost_handler);
+        repost.period(0);
+
+        let mut queue = EventQueue::new();
+        queue.bind(&second);
+        queue.bind(&first);
+        queue.bind(&repost);
+
+        first.call();
+        repost.call();
+
+        queue.run_once(0);
+        assert_eq!(*order.borrow(), [1, 3, 2]);
+
+        queue.run_once(1);
+        assert_eq!(*order.borrow(), [1, 3, 2, 3]);
+    }
+
+    #[test]
+    fn test_timer_handle() {
+        let count = Cell::new(0);
+        let handler = || count.set(count.get() + 1);
+
+        let event = Event::new(&handler);
+        let mut queue = EventQueue::new();
+        queue.bind(&event);
+
+        let timer = event.call_on(10);
+        assert!(timer.is_pending());
+        assert!(timer.cancel());
+        assert!(!timer.is_pending());
+        assert!(!timer.cancel());
+
+        queue.run_once(10);
+        assert_eq!(count.get(), 0);
+
+        let timer = event.call_on(20);
+        queue.run_once(20);
+        assert_eq!(count.get(), 1);
+        // Dispatched, nothing to cancel.
+        assert!(!timer.cancel());
+        assert!(timer.reschedule(30).is_none());
+    }
+
+    #[test]
+    fn test_timer_handle_stale_after_repost() {
+        let count = Cell::new(0);
+        let handler = || count.set(count.get() + 1);
+
+        let event = Event::new(&handler);
+        let mut queue = EventQueue::new();
+        queue.bind(&event);
+
+        let old_timer = event.call_on(10);
+        let timer = event.call_on(20);
+
+        // Doesn't cancel the newer post.
+        assert!(!old_timer.cancel());
+        assert!(timer.is_pending());
+
+        let timer = timer.reschedule(30).unwrap();
+        queue.run_once(20);
+        assert_eq!(count.get(), 0);
+        assert!(timer.is_pending());
+
+        queue.run_once(30);
+        assert_eq!(count.get(), 1);
+        assert!(!timer.is_pending());
+    }
+
+    #[test]
+    fn test_dispatch_priority() {
+        let order = RefCell::new(std::vec::Vec::new());
+
+        let low_handler = || order.borrow_mut().push(1);
+        let high_handler = || order.borrow_mut().push(2);
+        let later_handler = || order.borrow_mut().push(3);
+
+        let low = Event::new(&low_handler);
+        let high = Event::new(&high_handler).with_priority(1);
+        let later = Event::new(&later_handler).with_priority(2);
+
+        let mut queue = EventQueue::new();
+        queue.bind(&low);
+        queue.bind(&high);
+        queue.bind(&later);
+
+        low.call_on(20);
+        high.call_on(50);
+        // Not due yet, priority doesn't matter.
+        later.call_on(200);
+
+        queue.run_once(100);
+        assert_eq!(*order.borrow(), [2, 1]);
+
+        queue.run_once(200);
+        assert_eq!(*order.borrow(), [2, 1, 3]);
+    }
+
+    #[test]
+    fn test_error_hook() {
+        let errors = RefCell::new(std::vec::Vec::new());
+        let attempts = Cell::new(0);
+
+        let handler = || {
+            attempts.set(attempts.get() + 1);
+            if attempts.get() < 3 {
+                Err(attempts.get())
+            } else {
+                Ok(())
+            }
+        };
+        let hook = |error| {
+            errors.borrow_mut().push(error);
+            ErrorAction::Retry(10)
+        };
+
+        let event = Event::new_fallible(&handler);
+        let mut queue = EventQueue::new();
+        queue.set_error_hook(&hook);
+        queue.bind(&event);
+
+        event.call();
+        queue.run_once(0);
+        assert_eq!(*errors.borrow(), [1]);
+
+        queue.run_once(9);
+        assert_eq!(attempts.get(), 1);
+
+        queue.run_once(10);
+        assert_eq!(*errors.borrow(), [1, 2]);
+
+        queue.run_once(20);
+        assert_eq!(attempts.get(), 3);
+        assert_eq!(*errors.borrow(), [1, 2]);
+
+        // Succeeded, not rescheduled.
+        queue.run_once(30);
+        assert_eq!(attempts.get(), 3);
+    }
+
+    #[test]
+    fn test_error_hook_ignore() {
+        let attempts = Cell::new(0);
+
+        let mut handler = || {
+            attempts.set(attempts.get() + 1);
+            Err(())
+        };
+        let hook = |_| ErrorAction::Ignore;
+
+        let event = Event::new_fallible_mut(&mut handler);
+        event.period(100);
+
+        let mut queue = EventQueue::new();
+        queue.set_error_hook(&hook);
+        queue.bind(&event);
+
+        event.call_on(100);
+        queue.run_once(100);
+        assert_eq!(attempts.get(), 1);
+
+        // Periodic event keeps its schedule.
+        queue.run_once(150);
+        assert_eq!(attempts.get(), 1);
+
+        queue.run_once(200);
+        assert_eq!(attempts.get(), 2);
+    }
+
+    #[test]
+    #[should_panic]
+    fn test_error_without_hook() {
+        let handler = || Err(());
+
+        let event = Event::new_fallible(&handler);
+        let mut queue = EventQueue::new();
+        queue.bind(&event);
+
+        event.call();
+        queue.run_once(0);
+    }
+
+    #[cfg(feature = "stats")]
+    #[test]
+    fn test_stats() {
+        let clock = Cell::new(0);
+        let durations = [5, 20, 11];
+        let run = Cell::new(0);
+
+        let handler = || {
+            clock.set(clock.get() + durations[run.get()]);
+            run.set(run.get() + 1);
+        };
+        let now = || clock.get();
+
+        let event = Event::new(&handler);
+        event.period(100);
+
+        let mut queue = EventQueue::new();
+        queue.set_clock(&now);
+        queue.bind(&event);
+
+        assert_eq!(event.stats(), EventStats::default());
+
+        event.call_on(100);
+        queue.run_once(100);
+        queue.run_once(150);
+        // Late by more than a period.
+        queue.run_once(320);
+        queue.run_once(330);
+
+        let stats = event.stats();
+        assert_eq!(stats.dispatches, 3);
+        assert_eq!(stats.total_duration, 36);
+        assert_eq!(stats.max_duration, 20);
+        assert_eq!(stats.avg_duration(), 12);
+        assert_eq!(stats.missed_deadlines, 1);
+
+        event.reset_stats();
+        assert_eq!(event.stats(), EventStats::default());
+    }
+
+    #[cfg(feature = "stats")]
+    #[test]
+    fn test_stats_without_clock() {
+        let handler = || {};
+
+        let event = Event::new(&handler);
+        let mut queue = EventQueue::new();
+        queue.bind(&event);
+
+        event.call();
+        queue.run_once(0);
+
+        let stats = event.stats();
+        assert_eq!(stats.dispatches, 1);
+        assert_eq!(stats.total_duration, 0);
+        assert_eq!(stats.avg_duration(), 0);
+    }
+
+    #[test]
+    fn test_unbind() {
+        let done = Cell::new(0);
+
+        let handler = || {
+            done.set(done.get() + 1);
+        };
+
+        let event = Event::new(&handler);
+        let other_event = Event::new(&handler);
+
+        let mut queue = EventQueue::new();
+        queue.bind(&event);
+
+        assert!(!queue.unbind(&other_event));
+
+        event.call();
+        assert!(queue.unbind(&event));
+        assert!(!queue.unbind(&event));
+
+        queue.run_once(0);
+        assert_eq!(done.get(), 0);
+
+        // Unbound event isn't pending anymore, even in another queue.
+        let mut other_queue = EventQueue::new();
+        other_queue.bind(&event);
+        other_queue.run_once(10);
+        assert_eq!(done.get(), 0);
+
+        event.call();
+        other_queue.run_once(20);
+        assert_eq!(done.get(), 1);
+    }
+
+    #[test]
+    fn test_rebind_after_drop() {
+        let done = Cell::new(false);
+
+        let handler = || {
+            done.set(true);
+        };
+
+        let event = Event::new(&handler);
+
+        {
+            let mut queue = EventQueue::new();
+            queue.bind(&event);
+        }
+
+        let mut queue = EventQueue::new();
+        queue.bind(&event);
+
+        event.call();
+        queue.run_once(0);
+        assert!(done.get());
+    }
+
+    #[test]
+    #[should_panic]
+    fn test_bind_twice() {
+        let handler = || {};
+        let event = Event::new(&handler);
+
+        let mut queue = EventQueue::new();
+        let mut other_queue = EventQueue::new();
+
+        queue.bind(&event);
+        other_queue.bind(&event);
+    }
+
+    #[test]
+    fn test_next_deadline() {
+        let handler = || {};
+
+        let event = Event::new(&handler);
+        let other_event = Event::new(&handler);
+
+        let mut queue = EventQueue::new();
+        queue.bind(&event);
+        queue.bind(&other_event);
+
+        assert_eq!(queue.next_deadline(0), None);
+
+        event.call_on(100);
+        other_event.call_on(70);
+        assert_eq!(queue.next_deadline(0), Some(70));
+
+        other_event.call();
+        assert_eq!(queue.next_deadline(10), Some(10));
+
+        queue.run_once(10);
+        assert_eq!(queue.next_deadline(10), Some(100));
+
+        event.cancel();
+        assert_eq!(queue.next_deadline(10), None);
+    }
+
+    #[test]
+    fn test_wake_hook() {
+        let wakes = Cell::new(0);
+        let wake = || wakes.set(wakes.get() + 1);
+
+        let handler = || {};
+        let event = Event::new(&handler);
+        let unbound_event = Event::new(&handler);
+
+        let mut queue = EventQueue::new();
+        queue.bind(&event);
+        // Hook applies to events bound before it was set.
+        queue.set_wake_hook(&wake);
+
+        event.call();
+        event.call_on(100);
+        unbound_event.call();
+        assert_eq!(wakes.get(), 2);
+
+        // Dispatch doesn't wake the queue.
+        queue.run_once(100);
+        assert_eq!(wakes.get(), 2);
+
+        assert!(queue.unbind(&event));
+        event.call();
+        assert_eq!(wakes.get(), 2);
+    }
+
+    #[test]
+    fn test_priority_queues() {
+        let high_pending = Cell::new(false);
+        let wake_high = || high_pending.set(true);
+
+        let high_runs = Cell::new(0);
+        let high_handler = || high_runs.set(high_runs.get() + 1);
+        let high_event = Event::new(&high_handler);
+
+        // Low priority handler posts an event for the high priority queue.
+        let low_handler = || {
+            high_event.call();
+        };
+        let low_event = Event::new(&low_handler);
+
+        let mut high_queue = EventQueue::new();
+        high_queue.set_wake_hook(&wake_high);
+        high_queue.bind(&high_event);
+
+        let mut low_queue = EventQueue::new();
+        low_queue.bind(&low_event);
+
+        low_event.call();
+        assert!(!high_pending.get());
+
+        low_queue.run_once(0);
+        assert!(high_pending.get());
+        assert_eq!(high_runs.get(), 0);
+
+        // Events only run in the queue they are bound to.
+        low_queue.run_once(1);
+        assert_eq!(high_runs.get(), 0);
+
+        high_queue.run_once(1);
+        assert_eq!(high_runs.get(), 1);
+    }
+}
+
+#[cfg(test)]
+mod static_tests {
+    use super::*;
+
+    static DONE: Mutex<Cell<bool>> = Mutex::new(Cell::new(false));
+
+    fn handler() {
+        critical_section::with(|cs| {
+            DONE.borrow(cs).set(true);
+        });
+    }
+
+    static EVENT: Event = Event::new(&handler);
+
+    static SUM: Mutex<Cell<u32>> = Mutex::new(Cell::new(0));
+
+    fn channel_handler() {
+        while let Some(value) = CHANNEL.take() {
+            critical_section::with(|cs| {
+                let sum = SUM.borrow(cs);
+                sum.set(sum.get() + value);
+            });
+        }
+    }
+
+    static CHANNEL_EVENT: Event = Event::new(&channel_handler);
+    static CHANNEL: Channel<u32, 4> = Channel::new(&CHANNEL_EVENT);
+
+    #[test]
+    fn test_post_static_channel() {
+        let mut queue = EventQueue::new();
+
+        queue.bind(&CHANNEL_EVENT);
+        CHANNEL.post(3).unwrap();
+        CHANNEL.post(4).unwrap();
+        queue.run_once(0);
+
+        let sum = critical_section::with(|cs| SUM.borrow(cs).get());
+
+        assert_eq!(sum, 7);
+    }
+
+    #[test]
+    fn test_post_static_event() {
+        let mut queue = EventQueue::new();
+
+        queue.bind(&EVENT);
+        EVENT.call();
+        queue.run_once(0);
+
+        let done = critical_section::with(|cs| DONE.borrow(cs).get());
+
+        assert!(done);
+    }
+
+    static BOUND_LATER_DONE: Mutex<Cell<bool>> = Mutex::new(Cell::new(false));
+
+    fn bound_later_handler() {
+        critical_section::with(|cs| {
+            BOUND_LATER_DONE.borrow(cs).set(true);
+        });
+    }
+
+    static BOUND_LATER_EVENT: Event = Event::new(&bound_later_handler);
+
+    fn binding_handler() {
+        STATIC_QUEUE.bind(&BOUND_LATER_EVENT);
+        BOUND_LATER_EVENT.call();
+    }
+
+    static BINDING_EVENT: Event = Event::new(&binding_handler);
+    static STATIC_QUEUE: StaticEventQueue = StaticEventQueue::new();
+
+    #[test]
+    fn test_static_queue_bind_from_handler() {
+        // SAFETY: STATIC_QUEUE is only used by this test.
+        unsafe { static_queue_bind_from_handler() }
+    }
+
+    unsafe fn static_queue_bind_from_handler() {
+        STATIC_QUEUE.bind(&BINDING_EVENT);
+        BINDING_EVENT.call();
+        assert_eq!(STATIC_QUEUE.next_deadline(0), Some(0));
+
+        STATIC_QUEUE.run_once(0);
+        // Event bound by the handler waits for the next run.
+        assert!(!critical_section::with(|cs| BOUND_LATER_DONE
+            .borrow(cs)
+            .get()));
+        assert_eq!(STATIC_QUEUE.next_deadline(0), Some(0));
+
+        STATIC_QUEUE.run_once(0);
+        assert!(critical_section::with(|cs| BOUND_LATER_DONE
+            .borrow(cs)
+            .get()));
+        assert_eq!(STATIC_QUEUE.next_deadline(0), None);
+
+        assert!(STATIC_QUEUE.unbind(&BOUND_LATER_EVENT));
+        assert!(STATIC_QUEUE.unbind(&BINDING_EVENT));
+        assert!(!STATIC_QUEUE.unbind(&BINDING_EVENT));
     }
 }
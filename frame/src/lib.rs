@@ -0,0 +1,308 @@
+#![cfg_attr(not(test), no_std)]
+#![deny(unsafe_code)]
+
+// Framing for the serial link between send-flash-image and flash-writer.
+// Frame is sequence number, kind, payload and CRC-16 of all of them,
+// COBS encoded and terminated by a zero byte. Receiver can always find
+// the start of the next frame after garbage or a lost byte.
+
+pub const DELIMITER: u8 = 0;
+
+const HEADER_LEN: usize = 2;
+const CHECKSUM_LEN: usize = 2;
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Error {
+    BufferTooSmall,
+    InvalidEncoding,
+    TooShort,
+    ChecksumMismatch,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct Frame<'a> {
+    pub seq: u8,
+    pub kind: u8,
+    pub payload: &'a [u8],
+}
+
+// Buffer size needed to encode a frame, including the delimiter.
+pub const fn max_encoded_len(payload_len: usize) -> usize {
+    let len = HEADER_LEN + payload_len + CHECKSUM_LEN;
+    len + len / 254 + 2
+}
+
+// CRC-16/CCITT-FALSE
+fn crc16<'a>(data: impl Iterator<Item = &'a u8>) -> u16 {
+    data.fold(0xffff, |crc, &byte| {
+        (0..8).fold(crc ^ (u16::from(byte) << 8), |crc, _| {
+            if crc & 0x8000 != 0 {
+                (crc << 1) ^ 0x1021
+            } else {
+                crc << 1
+            }
+        })
+    })
+}
+
+impl<'a> Frame<'a> {
+    pub fn new(seq: u8, kind: u8, payload: &'a [u8]) -> Self {
+        Frame { seq, kind, payload }
+    }
+
+    fn checksum(&self) -> u16 {
+        let header = [self.seq, self.kind];
+        crc16(header.iter().chain(self.payload.iter()))
+    }
+
+    // Encode the frame into `out`, returns the number of bytes used.
+    pub fn encode(&self, out: &mut [u8]) -> Result<usize, Error> {
+        if out.len() < max_encoded_len(self.payload.len()) {
+            return Err(Error::BufferTooSmall);
+        }
+
+        let header = [self.seq, self.kind];
+        let checksum = self.checksum().to_be_bytes();
+        let data = header
+            .iter()
+            .chain(self.payload.iter())
+            .chain(checksum.iter());
+
+        // Each block starts with a code: offset to the next zero, or 0xff for
+        // a full block of 254 bytes without a zero after it.
+        let mut code_pos = 0;
+        let mut pos = 1;
+        let mut code = 1;
+        for &byte in data {
+            if byte != 0 {
+                out[pos] = byte;
+                pos += 1;
+                code += 1;
+            }
+
+            if byte == 0 || code == 0xff {
+                out[code_pos] = code;
+                code_pos = pos;
+                pos += 1;
+                code = 1;
+            }
+        }
+
+        out[code_pos] = code;
+        out[pos] = DELIMITER;
+
+        Ok(pos + 1)
+    }
+
+    // Decode frame received without the delimiter. Data is decoded in place.
+    pub fn decode(data: &'a mut [u8]) -> Result<Self, Error> {
+        let mut read = 0;
+        let mut write = 0;
+
+        while read < data.len() {
+            let code = usize::from(data[read]);
+            let end = read + code;
+            if code == 0 || end > data.len() {
+                return Err(Error::InvalidEncoding);
+            }
+
+            data.copy_within(read + 1..end, write);
+            write += code - 1;
+            read = end;
+
+            if code != 0xff && read < data.len() {
+                data[write] = 0;
+                write += 1;
+            }
+        }
+
+        if write < HEADER_LEN + CHECKSUM_LEN {
+            return Err(Error::TooShort);
+        }
+
+        let (data, checksum) = data[..write].split_at(write - CHECKSUM_LEN);
+        let frame = Frame {
+            seq: data[0],
+            kind: data[1],
+            payload: &data[HEADER_LEN..],
+        };
+
+        if frame.checksum() != u16::from_be_bytes([checksum[0], checksum[1]]) {
+            return Err(Error::ChecksumMismatch);
+        }
+
+        Ok(frame)
+    }
+}
+
+// Collects received bytes into frames.
+pub struct Receiver<const N: usize> {
+    buffer: [u8; N],
+    len: usize,
+    // Frame didn't fit, the rest of it is dropped.
+    overflow: bool,
+}
+
+impl<const N: usize> Receiver<N> {
+    pub const fn new() -> Self {
+        Receiver {
+            buffer: [0; N],
+            len: 0,
+            overflow: false,
+        }
+    }
+
+    // Add a byte, returns the frame when it is complete.
+    pub fn push(&mut self, byte: u8) -> Option<Result<Frame<'_>, Error>> {
+        if byte != DELIMITER {
+            if self.len < N {
+                self.buffer[self.len] = byte;
+                self.len += 1;
+            } else {
+                self.overflow = true;
+            }
+
+            return None;
+        }
+
+        let len = core::mem::replace(&mut self.len, 0);
+        if core::mem::replace(&mut self.overflow, false) {
+            return Some(Err(Error::BufferTooSmall));
+        }
+        if len == 0 {
+            // Back to back delimiters, e.g. sent to resynchronize.
+            return None;
+        }
+
+        Some(Frame::decode(&mut self.buffer[..len]))
+    }
+}
+
+impl<const N: usize> Default for Receiver<N> {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn roundtrip(payload: &[u8]) {
+        let mut buffer = vec![0; max_encoded_len(payload.len())];
+        let len = Frame::new(7, b'W', payload).encode(&mut buffer).unwrap();
+
+        assert_eq!(buffer[len - 1], DELIMITER);
+        assert!(!buffer[..len - 1].contains(&DELIMITER));
+
+        let frame = Frame::decode(&mut buffer[..len - 1]).unwrap();
+        assert_eq!(frame, Frame::new(7, b'W', payload));
+    }
+
+    #[test]
+    fn test_crc16() {
+        assert_eq!(crc16(b"123456789".iter()), 0x29b1);
+    }
+
+    #[test]
+    fn test_roundtrip() {
+        roundtrip(&[]);
+        roundtrip(&[0]);
+        roundtrip(&[0, 0, 0]);
+        roundtrip(b"hello");
+        roundtrip(&(0..=255).collect::<Vec<u8>>());
+        roundtrip(&[1; 253]);
+        roundtrip(&[1; 254]);
+        roundtrip(&[1; 255]);
+        roundtrip(&[1; 4096]);
+        roundtrip(&[0; 4096]);
+    }
+
+    #[test]
+    fn test_encode() {
+        let mut buffer = [0; 16];
+        let len = Frame::new(0, 1, &[2]).encode(&mut buffer).unwrap();
+
+        // Header 00 01, payload 02, checksum.
+        let checksum = crc16([0, 1, 2].iter()).to_be_bytes();
+        assert_eq!(&buffer[..len], &[1, 5, 1, 2, checksum[0], checksum[1], 0]);
+    }
+
+    #[test]
+    fn test_buffer_too_small() {
+        let mut buffer = [0; 8];
+        assert_eq!(
+            Frame::new(0, 0, &[1; 8]).encode(&mut buffer),
+            Err(Error::BufferTooSmall)
+        );
+    }
+
+    #[test]
+    fn test_corrupted() {
+        let mut buffer = [0; 32];
+        let len = Frame::new(1, 2, b"data").encode(&mut buffer).unwrap();
+
+        let mut corrupted = buffer;
+        corrupted[3] ^= 0x10;
+        assert_eq!(
+            Frame::decode(&mut corrupted[..len - 1]),
+            Err(Error::ChecksumMismatch)
+        );
+
+        // Lost byte.
+        let mut truncated = buffer;
+        truncated.copy_within(4..len, 3);
+        assert!(Frame::decode(&mut truncated[..len - 2]).is_err());
+
+        assert_eq!(Frame::decode(&mut [2, 1]), Err(Error::TooShort));
+        assert_eq!(Frame::decode(&mut [5, 1]), Err(Error::InvalidEncoding));
+        assert_eq!(Frame::decode(&mut [0, 1]), Err(Error::InvalidEncoding));
+    }
+
+    #[test]
+    fn test_receiver() {
+        let mut stream = vec![0, 0x55, 0x55, 0];
+        let mut buffer = [0; 64];
+        for seq in 0..3 {
+            let len = Frame::new(seq, b'Q', &[seq, 0, 1])
+                .encode(&mut buffer)
+                .unwrap();
+            stream.extend_from_slice(&buffer[..len]);
+        }
+
+        let mut receiver = Receiver::<32>::new();
+        let mut frames = Vec::new();
+        for byte in stream {
+            if let Some(result) = receiver.push(byte) {
+                frames.push(result.map(|frame| frame.seq));
+            }
+        }
+
+        // Garbage before the first frame is reported and skipped.
+        assert_eq!(
+            frames,
+            vec![Err(Error::InvalidEncoding), Ok(0), Ok(1), Ok(2)]
+        );
+    }
+
+    #[test]
+    fn test_receiver_overflow() {
+        let mut buffer = [0; 64];
+        let len = Frame::new(0, 0, &[1; 20]).encode(&mut buffer).unwrap();
+
+        let mut receiver = Receiver::<8>::new();
+        let results: Vec<_> = buffer[..len]
+            .iter()
+            .filter_map(|&byte| receiver.push(byte).map(|r| r.map(|f| f.seq)))
+            .collect();
+        assert_eq!(results, vec![Err(Error::BufferTooSmall)]);
+
+        // Next frame is received normally.
+        let len = Frame::new(1, 0, &[]).encode(&mut buffer).unwrap();
+        let results: Vec<_> = buffer[..len]
+            .iter()
+            .filter_map(|&byte| receiver.push(byte).map(|r| r.map(|f| f.seq)))
+            .collect();
+        assert_eq!(results, vec![Ok(1)]);
+    }
+}
@@ -0,0 +1,225 @@
+#![cfg_attr(not(test), no_std)]
+#![deny(unsafe_code)]
+
+// Laser safety interlock. Decides if the laser may be on, independent of the hardware.
+// Time is in ticks of a wrapping counter.
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Denied {
+    // Turret is not standing on its base.
+    PickedUp,
+    // Safety button is pressed.
+    ButtonHeld,
+    // Laser was on for too long and has to stay off for a while.
+    CoolingDown,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct SafetyPolicy {
+    max_on_time: u32,
+    cooldown: u32,
+    picked_up: bool,
+    button_held: bool,
+    // Start of the current on period, None while the laser is off.
+    on_since: Option<u32>,
+    // Laser can't be turned on until this time.
+    cooldown_until: Option<u32>,
+}
+
+// Signed number of ticks from `since` to `now`, the counter may wrap around.
+fn elapsed(since: u32, now: u32) -> i32 {
+    now.wrapping_sub(since) as i32
+}
+
+impl SafetyPolicy {
+    // Laser may stay on for `max_on_time` continuously,
+    // then it is forced off for `cooldown`.
+    pub const fn new(max_on_time: u32, cooldown: u32) -> Self {
+        SafetyPolicy {
+            max_on_time,
+            cooldown,
+            picked_up: false,
+            button_held: false,
+            on_since: None,
+            cooldown_until: None,
+        }
+    }
+
+    pub fn set_picked_up(&mut self, picked_up: bool) {
+        self.picked_up = picked_up;
+    }
+
+    pub fn set_button_held(&mut self, button_held: bool) {
+        self.button_held = button_held;
+    }
+
+    pub fn is_on(&self) -> bool {
+        self.on_since.is_some()
+    }
+
+    // Check if the laser may be turned on now.
+    pub fn check(&self, now: u32) -> Result<(), Denied> {
+        if self.picked_up {
+            return Err(Denied::PickedUp);
+        }
+
+        if self.button_held {
+            return Err(Denied::ButtonHeld);
+        }
+
+        match self.cooldown_until {
+            Some(until) if elapsed(until, now) < 0 => Err(Denied::CoolingDown),
+            _ => Ok(()),
+        }
+    }
+
+    // Request the laser on. Keeps the start of the on period if it is on already.
+    pub fn turn_on(&mut self, now: u32) -> Result<(), Denied> {
+        self.update(now)?;
+        self.check(now)?;
+
+        if self.on_since.is_none() {
+            self.on_since = Some(now);
+        }
+
+        Ok(())
+    }
+
+    // Laser was turned off voluntarily.
+    pub fn turn_off(&mut self) {
+        self.on_since = None;
+    }
+
+    // Check if the laser may stay on. Laser is considered off after an error.
+    pub fn update(&mut self, now: u32) -> Result<(), Denied> {
+        let Some(on_since) = self.on_since else {
+            return Ok(());
+        };
+
+        if elapsed(on_since, now) >= self.max_on_time as i32 {
+            self.on_since = None;
+            self.cooldown_until = Some(now.wrapping_add(self.cooldown));
+
+            return Err(Denied::CoolingDown);
+        }
+
+        let result = self.check(now);
+        if result.is_err() {
+            self.on_since = None;
+        }
+
+        result
+    }
+
+    // Ticks left until the laser is forced off, None if it is off.
+    pub fn time_left(&self, now: u32) -> Option<u32> {
+        self.on_since.map(|on_since| {
+            let on_time = elapsed(on_since, now).max(0) as u32;
+            self.max_on_time.saturating_sub(on_time)
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_turn_on() {
+        let mut policy = SafetyPolicy::new(100, 50);
+
+        assert!(!policy.is_on());
+        assert_eq!(policy.turn_on(10), Ok(()));
+        assert!(policy.is_on());
+        assert_eq!(policy.time_left(30), Some(80));
+
+        // Repeated requests don't extend on time.
+        assert_eq!(policy.turn_on(60), Ok(()));
+        assert_eq!(policy.time_left(60), Some(50));
+
+        policy.turn_off();
+        assert!(!policy.is_on());
+        assert_eq!(policy.time_left(60), None);
+    }
+
+    #[test]
+    fn test_max_on_time() {
+        let mut policy = SafetyPolicy::new(100, 50);
+
+        assert_eq!(policy.turn_on(0), Ok(()));
+        assert_eq!(policy.update(99), Ok(()));
+        assert_eq!(policy.update(100), Err(Denied::CoolingDown));
+        assert!(!policy.is_on());
+
+        assert_eq!(policy.turn_on(120), Err(Denied::CoolingDown));
+        assert_eq!(policy.turn_on(149), Err(Denied::CoolingDown));
+        assert_eq!(policy.turn_on(150), Ok(()));
+        assert_eq!(policy.time_left(150), Some(100));
+    }
+
+    #[test]
+    fn test_turn_on_after_max_on_time() {
+        let mut policy = SafetyPolicy::new(100, 50);
+
+        assert_eq!(policy.turn_on(0), Ok(()));
+        // Nobody checked in time, still can't stay on.
+        assert_eq!(policy.turn_on(130), Err(Denied::CoolingDown));
+        assert!(!policy.is_on());
+        assert_eq!(policy.turn_on(180), Ok(()));
+    }
+
+    #[test]
+    fn test_voluntary_off() {
+        let mut policy = SafetyPolicy::new(100, 50);
+
+        assert_eq!(policy.turn_on(0), Ok(()));
+        policy.turn_off();
+
+        // No cooldown, on time starts over.
+        assert_eq!(policy.turn_on(90), Ok(()));
+        assert_eq!(policy.update(150), Ok(()));
+        assert_eq!(policy.update(190), Err(Denied::CoolingDown));
+    }
+
+    #[test]
+    fn test_picked_up() {
+        let mut policy = SafetyPolicy::new(100, 50);
+
+        assert_eq!(policy.turn_on(0), Ok(()));
+        policy.set_picked_up(true);
+        assert_eq!(policy.update(10), Err(Denied::PickedUp));
+        assert!(!policy.is_on());
+        assert_eq!(policy.turn_on(20), Err(Denied::PickedUp));
+
+        policy.set_picked_up(false);
+        assert_eq!(policy.turn_on(30), Ok(()));
+    }
+
+    #[test]
+    fn test_button_held() {
+        let mut policy = SafetyPolicy::new(100, 50);
+
+        policy.set_button_held(true);
+        assert_eq!(policy.check(0), Err(Denied::ButtonHeld));
+        assert_eq!(policy.turn_on(0), Err(Denied::ButtonHeld));
+        assert!(!policy.is_on());
+
+        policy.set_button_held(false);
+        assert_eq!(policy.turn_on(10), Ok(()));
+        policy.set_button_held(true);
+        assert_eq!(policy.update(20), Err(Denied::ButtonHeld));
+        assert!(!policy.is_on());
+    }
+
+    #[test]
+    fn test_wraparound() {
+        let mut policy = SafetyPolicy::new(100, 50);
+
+        assert_eq!(policy.turn_on(u32::MAX - 49), Ok(()));
+        assert_eq!(policy.time_left(10), Some(40));
+        assert_eq!(policy.update(49), Ok(()));
+        assert_eq!(policy.update(50), Err(Denied::CoolingDown));
+        assert_eq!(policy.turn_on(99), Err(Denied::CoolingDown));
+        assert_eq!(policy.turn_on(100), Ok(()));
+    }
+}
@@ -1,34 +1,104 @@
 #![deny(unsafe_code)]
 
+use std::cmp::min;
 use std::error::Error;
-use std::fs::OpenOptions;
-use std::io::{Read, Write};
+use std::io::{ErrorKind, Read, Write};
+use std::path::PathBuf;
+use std::time::Duration;
 
 use anyhow::Result;
-use clap::Parser;
+use clap::{Parser, Subcommand};
 use crc::*;
+use frame::{max_encoded_len, Frame, Receiver};
+use serialport::{ClearBuffer, SerialPort};
 
-/// Send filesystem image to the device
+const BAUD_RATE: u32 = 115200;
+// Long enough to receive a block and write it to flash.
+const REPLY_TIMEOUT: Duration = Duration::from_secs(2);
+
+// Reply kinds, with the sequence number of the request.
+const ACK: u8 = 42;
+const NACK: u8 = 88;
+
+// Requests, see flash-writer for the payloads.
+const CMD_START: u8 = b'I';
+const CMD_BEGIN: u8 = b'B';
+const CMD_QUERY_CRC: u8 = b'Q';
+const CMD_SKIP: u8 = b'S';
+const CMD_WRITE: u8 = b'W';
+const CMD_DONE: u8 = b'D';
+const CMD_READ: u8 = b'R';
+
+// Largest read the device supports.
+const READ_LEN: usize = 1024;
+const FLASH_SIZE: u32 = 2 * 1024 * 1024;
+
+// Number of attempts to send a request before giving up.
+const MAX_ATTEMPTS: usize = 3;
+
+/// Transfer filesystem image to and from the device
 #[derive(Parser, Debug)]
 #[command(about)]
 struct Args {
     /// Serial port
     #[arg(short, default_value = "/dev/ttyACM0")]
-    serial_port: std::path::PathBuf,
-    /// Image file name
-    image: std::path::PathBuf,
+    serial_port: String,
+    #[command(subcommand)]
+    command: Command,
+}
+
+#[derive(Subcommand, Debug)]
+enum Command {
+    /// Send image to the device
+    Send {
+        /// Write all blocks, even if they are already on the device
+        #[arg(short, long)]
+        force: bool,
+        /// Continue interrupted transfer of the same image
+        #[arg(short, long)]
+        resume: bool,
+        /// Image file name
+        image: PathBuf,
+    },
+    /// Read flash contents into a file
+    Dump {
+        /// Number of bytes to read, multiple of 4
+        #[arg(short, long, default_value_t = FLASH_SIZE)]
+        len: u32,
+        /// Output file name
+        output: PathBuf,
+    },
+    /// Compare flash contents with an image
+    Diff {
+        /// Image file name
+        image: PathBuf,
+    },
 }
 
 #[derive(Debug, Copy, Clone, PartialEq, Eq)]
 enum SendError {
-    InvalidAck(u8),
+    InvalidReply(usize),
+    TooManyRetries(u8),
+    VerifyFailed(usize),
+    ReadCrcMismatch(u32),
+    ImagesDiffer(usize),
 }
 
 impl std::fmt::Display for SendError {
     fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
         match self {
-            SendError::InvalidAck(received_ack) => {
-                f.write_fmt(format_args!("InvalidAck({})", received_ack))
+            SendError::InvalidReply(len) => f.write_fmt(format_args!("InvalidReply({})", len)),
+            SendError::TooManyRetries(command) => {
+                f.write_fmt(format_args!("TooManyRetries({})", *command as char))
+            }
+            SendError::VerifyFailed(num_blocks) => {
+                f.write_fmt(format_args!("VerifyFailed({})", num_blocks))
+            }
+            SendError::ReadCrcMismatch(address) => {
+                f.write_fmt(format_args!("ReadCrcMismatch({:x})", address))
+            }
+            SendError::ImagesDiffer(num_blocks) => {
+                f.write_fmt(format_args!("ImagesDiffer({})", num_blocks))
             }
         }
     }
@@ -36,49 +106,275 @@ impl std::fmt::Display for SendError {
 
 impl Error for SendError {}
 
-fn main() -> Result<()> {
-    let args = Args::parse();
+fn checksum(data: &[u8]) -> u32 {
+    Crc::<u32>::new(&CRC_32_MPEG_2).checksum(data)
+}
+
+fn u32_at(payload: &[u8], offset: usize) -> Result<u32> {
+    let bytes = payload
+        .get(offset..offset + 4)
+        .ok_or(SendError::InvalidReply(payload.len()))?;
+
+    Ok(u32::from_be_bytes(bytes.try_into()?))
+}
+
+struct Device {
+    port: Box<dyn SerialPort>,
+    seq: u8,
+}
+
+impl Device {
+    fn open(path: &str) -> Result<Self> {
+        let port = serialport::new(path, BAUD_RATE)
+            .timeout(REPLY_TIMEOUT)
+            .open()?;
+
+        Ok(Device { port, seq: 0 })
+    }
+
+    // Wait for the reply to the request with this sequence number.
+    // Returns None if the request has to be sent again.
+    fn read_reply(&mut self, seq: u8) -> Result<Option<Vec<u8>>> {
+        let mut receiver = Receiver::<{ max_encoded_len(4 + READ_LEN) }>::new();
+        let mut byte = [0; 1];
+
+        loop {
+            match self.port.read(&mut byte) {
+                Ok(0) => continue,
+                Ok(_) => {}
+                Err(err) if err.kind() == ErrorKind::TimedOut => {
+                    println!("Timed out waiting for reply");
+                    return Ok(None);
+                }
+                Err(err) => Err(err)?,
+            }
+
+            match receiver.push(byte[0]) {
+                None => {}
+                Some(Err(err)) => {
+                    println!("Corrupted reply: {:?}", err);
+                    return Ok(None);
+                }
+                // Reply to an earlier attempt, its request was sent again.
+                Some(Ok(reply)) if reply.kind == ACK && reply.seq != seq => {}
+                Some(Ok(reply)) if reply.kind == ACK => return Ok(Some(reply.payload.to_vec())),
+                Some(Ok(reply)) if reply.kind == NACK => {
+                    println!("Device rejected the request");
+                    return Ok(None);
+                }
+                Some(Ok(reply)) => Err(SendError::InvalidReply(reply.payload.len()))?,
+            }
+        }
+    }
+
+    // Send request and return the reply payload, resending if either gets corrupted.
+    fn request(&mut self, kind: u8, payload: &[u8]) -> Result<Vec<u8>> {
+        self.seq = self.seq.wrapping_add(1);
+
+        let mut buffer = vec![0; max_encoded_len(payload.len())];
+        let len = Frame::new(self.seq, kind, payload)
+            .encode(&mut buffer)
+            .map_err(|err| anyhow::anyhow!("Cannot encode request: {:?}", err))?;
+
+        for _ in 0..MAX_ATTEMPTS {
+            self.port.clear(ClearBuffer::Input)?;
+            self.port.write_all(&buffer[..len])?;
+
+            if let Some(reply) = self.read_reply(self.seq)? {
+                return Ok(reply);
+            }
+            println!("Retrying");
+        }
+
+        Err(SendError::TooManyRetries(kind).into())
+    }
+
+    fn block_crc(&mut self, index: usize) -> Result<u32> {
+        let reply = self.request(CMD_QUERY_CRC, &(index as u32).to_be_bytes())?;
+        u32_at(&reply, 0)
+    }
+
+    // Read up to READ_LEN bytes of flash.
+    fn read(&mut self, address: u32, len: usize) -> Result<Vec<u8>> {
+        let mut request = address.to_be_bytes().to_vec();
+        request.extend_from_slice(&(len as u16).to_be_bytes());
+
+        let reply = self.request(CMD_READ, &request)?;
+        if reply.len() != 4 + len {
+            Err(SendError::InvalidReply(reply.len()))?;
+        }
+
+        let data = reply[4..].to_vec();
+        if u32_at(&reply, 0)? != checksum(&data) {
+            Err(SendError::ReadCrcMismatch(address))?;
+        }
+
+        Ok(data)
+    }
 
-    let mut image = std::fs::read(args.image)?;
+    fn read_range(&mut self, len: u32) -> Result<Vec<u8>> {
+        let mut data = Vec::with_capacity(len as usize);
+
+        for address in (0..len).step_by(READ_LEN) {
+            let chunk_len = min(READ_LEN, (len - address) as usize);
+            print!("\rReading {:x} of {:x}", address, len);
+            std::io::stdout().flush()?;
+
+            data.extend(self.read(address, chunk_len)?);
+        }
+        println!();
+
+        Ok(data)
+    }
+}
+
+fn send_block(device: &mut Device, index: usize, chunk: &[u8]) -> Result<()> {
+    println!(
+        "Sending chunk {} of len {} with crc {:x}",
+        index,
+        chunk.len(),
+        checksum(chunk)
+    );
+
+    let mut payload = (index as u32).to_be_bytes().to_vec();
+    payload.extend_from_slice(chunk);
+    device.request(CMD_WRITE, &payload)?;
+
+    Ok(())
+}
+
+fn read_image(path: PathBuf) -> Result<Vec<u8>> {
+    let mut image = std::fs::read(path)?;
 
     if image.len() % 4 != 0 {
         // Image length must be a multiple of 4, STM CRC unit takes 32-bit inputs
         image.extend(vec![0; 4 - image.len() % 4]);
     }
 
-    let image_crc = Crc::<u32>::new(&CRC_32_MPEG_2).checksum(&image);
-
-    let mut device = OpenOptions::new()
-        .read(true)
-        .write(true)
-        .open(args.serial_port)?;
+    Ok(image)
+}
 
-    println!("Sending image size");
-    device.write_all((image.len() as u32).to_be_bytes().as_ref())?;
+fn send(device: &mut Device, image: &[u8], force: bool, resume: bool) -> Result<()> {
+    let image_crc = checksum(image);
 
-    println!("Reading block size");
-    let mut block_size_buf = [0; 2];
-    device.read_exact(&mut block_size_buf)?;
+    println!("Sending image size and crc");
+    let mut start = (image.len() as u32).to_be_bytes().to_vec();
+    start.extend_from_slice(&image_crc.to_be_bytes());
+    let reply = device.request(CMD_START, &start)?;
+    if reply.len() != 6 {
+        Err(SendError::InvalidReply(reply.len()))?;
+    }
 
-    let block_size = u16::from_be_bytes(block_size_buf).into();
+    let block_size = u16::from_be_bytes([reply[0], reply[1]]).into();
     println!("Block size: {}", block_size);
 
-    for chunk in image.chunks(block_size) {
-        let crc = Crc::<u32>::new(&CRC_32_MPEG_2).checksum(chunk);
-        println!("Sending chunk of len {} with crc {:x}", chunk.len(), crc);
-        device.write_all(chunk)?;
-        device.write_all(crc.to_be_bytes().as_ref())?;
+    let written_blocks = u32_at(&reply, 2)?;
+    println!("Blocks written previously: {}", written_blocks);
 
-        println!("Reading ack");
-        let mut ack = [0; 1];
-        device.read_exact(&mut ack)?;
+    let start_block = if resume { written_blocks } else { 0 };
+    device.request(CMD_BEGIN, &start_block.to_be_bytes())?;
 
-        if ack[0] != 42 {
-            Err(SendError::InvalidAck(ack[0]))?;
+    for (index, chunk) in image
+        .chunks(block_size)
+        .enumerate()
+        .skip(start_block as usize)
+    {
+        if !force && device.block_crc(index)? == checksum(chunk) {
+            println!("Chunk {} is unchanged, skipping", index);
+            device.request(CMD_SKIP, &(index as u32).to_be_bytes())?;
+            continue;
         }
+
+        send_block(device, index, chunk)?;
+    }
+
+    println!("Verifying");
+    let mut failed_blocks = 0;
+    for (index, chunk) in image.chunks(block_size).enumerate() {
+        let device_crc = device.block_crc(index)?;
+        let crc = checksum(chunk);
+
+        if device_crc != crc {
+            println!(
+                "Chunk {} mismatch: expected crc {:x}, device has {:x}",
+                index, crc, device_crc
+            );
+            failed_blocks += 1;
+        }
+    }
+
+    device.request(CMD_DONE, &[])?;
+
+    if failed_blocks > 0 {
+        Err(SendError::VerifyFailed(failed_blocks))?;
     }
 
     println!("Image crc: {:x}", image_crc);
 
     Ok(())
 }
+
+fn diff(device: &mut Device, image: &[u8]) -> Result<()> {
+    let contents = device.read_range(image.len() as u32)?;
+
+    let mut different_blocks = 0;
+    for (index, (expected, actual)) in image
+        .chunks(READ_LEN)
+        .zip(contents.chunks(READ_LEN))
+        .enumerate()
+    {
+        let mut offsets = expected
+            .iter()
+            .zip(actual.iter())
+            .enumerate()
+            .filter(|(_, (e, a))| e != a)
+            .map(|(offset, _)| index * READ_LEN + offset);
+
+        let Some(first) = offsets.next() else {
+            continue;
+        };
+        let last = offsets.next_back().unwrap_or(first);
+
+        println!(
+            "Chunk {} differs at {:x}..={:x}: expected crc {:x}, device has {:x}",
+            index,
+            first,
+            last,
+            checksum(expected),
+            checksum(actual)
+        );
+        different_blocks += 1;
+    }
+
+    if different_blocks > 0 {
+        Err(SendError::ImagesDiffer(different_blocks))?;
+    }
+
+    println!("Device contents match the image");
+
+    Ok(())
+}
+
+fn main() -> Result<()> {
+    let args = Args::parse();
+
+    let mut device = Device::open(&args.serial_port)?;
+
+    match args.command {
+        Command::Send {
+            force,
+            resume,
+            image,
+        } => send(&mut device, &read_image(image)?, force, resume),
+        Command::Dump { len, output } => {
+            if len % 4 != 0 {
+                anyhow::bail!("Length must be a multiple of 4");
+            }
+
+            let contents = device.read_range(len)?;
+            std::fs::write(output, contents)?;
+            Ok(())
+        }
+        Command::Diff { image } => diff(&mut device, &read_image(image)?),
+    }
+}
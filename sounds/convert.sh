@@ -3,11 +3,32 @@
 targetdir="$1"
 if [ -z $targetdir ] ; then
   echo "Usage: $0 <targetdir>"
+  echo "Set RATE and BITS (8, 16 or 4 for IMA ADPCM) to change clip format, default is 16000 Hz 8 bit."
   exit 1
 fi
 
+rate=${RATE:-16000}
+bits=${BITS:-8}
+if [ $bits = 8 ] ; then
+  encoding=unsigned-integer
+else
+  encoding=signed-integer
+fi
+
+encode() {
+  if [ $bits = 4 ] ; then
+    sox "$1" -b 16 -e ${encoding} -L -c 1 -r ${rate} -t raw - | cargo run -q --release --bin adpcm-encode
+  else
+    sox "$1" -b ${bits} -e ${encoding} -L -c 1 -r ${rate} -t raw -
+  fi
+}
+
+# Header: "CLIP", sample rate (16 bit big endian), bits per sample, reserved.
+header=$(printf '\\%03o\\%03o\\%03o\\000' $((rate >> 8)) $((rate & 255)) ${bits})
+
 for file in *.wav ; do
   raw=${targetdir}/$(basename ${file} .wav).raw
   echo "${file} => ${raw}"
-  sox ${file} -b 8 -e unsigned-integer -c 1 -r 16000 ${raw}
+  printf "CLIP${header}" > ${raw}
+  encode ${file} >> ${raw}
 done